@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::registry::Registry;
+
+const BANS_FILE: &str = "bans.json";
+
+/// Who a ban applies to, as given by the operator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BanTarget {
+    Node(NodeId),
+    /// Matched ignoring case, against the registry and the nicknames peers advertise.
+    Nickname(String),
+}
+
+impl FromStr for BanTarget {
+    type Err = anyhow::Error;
+
+    /// Anything that parses as a node id is one, everything else is a nickname.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        anyhow::ensure!(!s.is_empty(), "Empty ban target");
+        Ok(match NodeId::from_str(s) {
+            Ok(node_id) => Self::Node(node_id),
+            Err(_) => Self::Nickname(s.to_owned()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Shown to the banned user.
+    pub reason: String,
+    /// Seconds since the unix epoch.
+    pub banned_at: u64,
+    /// Seconds since the unix epoch, `None` for a permanent ban. Kicks are bans that
+    /// expire.
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// A ban as distributed to peers. Nickname bans carry the node id the registry has for
+/// the nickname, so peers can refuse the node even if it advertises another nickname.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub node_id: Option<NodeId>,
+    pub nickname: Option<String>,
+    pub reason: String,
+    pub expires_at: Option<u64>,
+}
+
+/// Bans set by the operator with the `ban`, `kick` and `unban` commands.
+///
+/// The file is read again on every use, so a running host picks up changes made from
+/// the command line right away.
+#[derive(Debug)]
+pub struct BanList {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(BANS_FILE);
+        let bans = if path.exists() {
+            let data = std::fs::read(&path)?;
+            serde_json::from_slice(&data).context("Failed to parse ban list")?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { path, bans })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.bans)?;
+        // Write to a temporary file first so a crash never leaves a truncated ban list
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path).context("Failed to save ban list")?;
+        Ok(())
+    }
+
+    /// Bans `target`, for `duration_secs` if given, replacing any earlier ban of it.
+    pub fn ban(
+        &mut self,
+        target: BanTarget,
+        reason: String,
+        duration_secs: Option<u64>,
+    ) -> Result<()> {
+        let now = now();
+        self.bans
+            .retain(|ban| ban.target != target && ban.is_active(now));
+        self.bans.push(Ban {
+            target,
+            reason,
+            banned_at: now,
+            expires_at: duration_secs.map(|secs| now.saturating_add(secs)),
+        });
+        self.save()
+    }
+
+    /// Lifts the ban of `target`, returns whether there was one.
+    pub fn unban(&mut self, target: &BanTarget) -> Result<bool> {
+        let len = self.bans.len();
+        self.bans.retain(|ban| &ban.target != target);
+        let removed = self.bans.len() != len;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &Ban> {
+        let now = now();
+        self.bans.iter().filter(move |ban| ban.is_active(now))
+    }
+
+    /// The active ban of `node_id`, directly or through the nickname registered for it.
+    pub fn find(&self, node_id: &NodeId, registry: &Registry) -> Option<&Ban> {
+        let nickname = registry.by_node_id(node_id).map(|r| r.nickname.as_str());
+        self.active().find(|ban| match &ban.target {
+            BanTarget::Node(banned) => banned == node_id,
+            BanTarget::Nickname(banned) => {
+                nickname.is_some_and(|nickname| nickname.eq_ignore_ascii_case(banned))
+            }
+        })
+    }
+
+    /// The active ban of `nickname`, ignoring case.
+    pub fn find_nickname(&self, nickname: &str) -> Option<&Ban> {
+        self.active().find(|ban| {
+            matches!(&ban.target, BanTarget::Nickname(banned) if banned.eq_ignore_ascii_case(nickname))
+        })
+    }
+
+    /// The active bans in the form sent to peers.
+    pub fn entries(&self, registry: &Registry) -> Vec<BanEntry> {
+        self.active()
+            .map(|ban| match &ban.target {
+                BanTarget::Node(node_id) => BanEntry {
+                    node_id: Some(*node_id),
+                    nickname: registry.by_node_id(node_id).map(|r| r.nickname.clone()),
+                    reason: ban.reason.clone(),
+                    expires_at: ban.expires_at,
+                },
+                BanTarget::Nickname(nickname) => BanEntry {
+                    node_id: registry.by_nickname(nickname).map(|r| r.node_id),
+                    nickname: Some(nickname.clone()),
+                    reason: ban.reason.clone(),
+                    expires_at: ban.expires_at,
+                },
+            })
+            .collect()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
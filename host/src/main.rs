@@ -1,3 +1,157 @@
-fn main() {
-    println!("Hello, world!");
+mod bans;
+mod protocol;
+mod registry;
+
+use anyhow::{Context, Result};
+use iroh::protocol::Router;
+use iroh::{Endpoint, SecretKey};
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::bans::{BanList, BanTarget};
+use crate::protocol::{ALPN, RegistryProtocol};
+use crate::registry::Registry;
+
+const SECRET_KEY_FILE: &str = "secret.key";
+
+/// Directory holding the host's key and registry, `HERMES_HOST_DATA_DIR` or `./hermes-host`.
+fn data_dir() -> Result<PathBuf> {
+    let dir = std::env::var("HERMES_HOST_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("hermes-host"));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Loads the host's secret key, generating one on first start. The host must keep its
+/// node id across restarts since peers are configured with it.
+fn load_secret_key(data_dir: &Path) -> Result<SecretKey> {
+    let path = data_dir.join(SECRET_KEY_FILE);
+    if path.exists() {
+        #[cfg(unix)]
+        if let Err(err) = restrict_permissions(&path) {
+            warn!("Could not restrict access to {}: {err}", path.display());
+        }
+        let bytes: [u8; 32] = std::fs::read(&path)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid secret key file {}", path.display()))?;
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+    let secret_key = SecretKey::generate(rand::rngs::OsRng);
+    write_secret_key(&path, &secret_key).context("Failed to save secret key")?;
+    Ok(secret_key)
+}
+
+/// Creates the key file readable by us only, whoever reads the key can act as us.
+fn write_secret_key(path: &Path, secret_key: &SecretKey) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&secret_key.to_bytes())
+}
+
+/// Takes read access to a key file saved by an earlier version away from others.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        permissions.set_mode(0o600);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+const USAGE: &str = "Usage:
+  host                                  run the host
+  host ban <node id|nickname> <reason>  ban a node or nickname
+  host kick <node id|nickname> <minutes> <reason>
+                                        ban for a number of minutes
+  host unban <node id|nickname>         lift a ban or kick
+  host bans                             list the active bans";
+
+/// Runs a moderation command against the ban list in `data_dir`. A running host picks
+/// up the change with the next request it answers.
+fn moderate(data_dir: &Path, args: &[String]) -> Result<()> {
+    let mut bans = BanList::load(data_dir)?;
+    match args {
+        [command, target, reason @ ..] if command == "ban" && !reason.is_empty() => {
+            let target: BanTarget = target.parse()?;
+            bans.ban(target.clone(), reason.join(" "), None)?;
+            println!("Banned {target:?}");
+        }
+        [command, target, minutes, reason @ ..] if command == "kick" && !reason.is_empty() => {
+            let target: BanTarget = target.parse()?;
+            let minutes: u64 = minutes.parse().context("Invalid number of minutes")?;
+            let secs = minutes
+                .checked_mul(60)
+                .context("Number of minutes is too large")?;
+            bans.ban(target.clone(), reason.join(" "), Some(secs))?;
+            println!("Kicked {target:?} for {minutes} minutes");
+        }
+        [command, target] if command == "unban" => {
+            let target: BanTarget = target.parse()?;
+            if bans.unban(&target)? {
+                println!("Unbanned {target:?}");
+            } else {
+                println!("{target:?} was not banned");
+            }
+        }
+        [command] if command == "bans" => {
+            for ban in bans.active() {
+                println!(
+                    "{:?}\t{}\t{}",
+                    ban.target,
+                    ban.expires_at
+                        .map_or("permanent".to_owned(), |at| format!("until {at}")),
+                    ban.reason
+                );
+            }
+        }
+        _ => anyhow::bail!("{USAGE}"),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_line_number(true)
+        .init();
+
+    let data_dir = data_dir()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return moderate(&data_dir, &args);
+    }
+    let secret_key = load_secret_key(&data_dir)?;
+    let registry = Registry::load(&data_dir)?;
+
+    let mut builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .discovery_local_network();
+    // A fixed port lets peers on other subnets reach the host directly
+    if let Ok(bind_addr) = std::env::var("HERMES_HOST_BIND") {
+        let bind_addr: SocketAddrV4 = bind_addr.parse().context("Invalid HERMES_HOST_BIND")?;
+        builder = builder.bind_addr_v4(bind_addr);
+    }
+    let endpoint = builder.bind().await?;
+    info!("Host node id: {}", endpoint.node_id());
+
+    let router = Router::builder(endpoint)
+        .accept(ALPN, RegistryProtocol::new(registry, data_dir))
+        .spawn();
+
+    tokio::signal::ctrl_c().await?;
+    info!("Shutting down");
+    router.shutdown().await?;
+    Ok(())
 }
@@ -0,0 +1,182 @@
+use anyhow::Result;
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::NodeId;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::protocol::ProtocolHandler;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, trace, warn};
+
+use crate::bans::{BanEntry, BanList};
+use crate::registry::Registry;
+
+/// Keep in sync with `peer/src-tauri/src/network/host.rs`.
+pub const ALPN: &[u8] = b"hermes/host/0";
+
+/// Upper bound on the length prefix of a message.
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HostRequest {
+    /// Registers a nickname for the node id of the connection.
+    Register {
+        nickname: String,
+    },
+    LookupNode {
+        node_id: NodeId,
+    },
+    LookupNickname {
+        nickname: String,
+    },
+    Quit,
+    /// The active bans, which peers enforce against each other.
+    ListBans,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HostResponse {
+    Registered,
+    Rejected {
+        reason: String,
+    },
+    /// Answer to a lookup, `None` if nothing is registered.
+    Registration {
+        node_id: Option<NodeId>,
+        nickname: Option<String>,
+    },
+    Bans {
+        bans: Vec<BanEntry>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct RegistryProtocol {
+    registry: Arc<Mutex<Registry>>,
+    data_dir: PathBuf,
+}
+
+impl RegistryProtocol {
+    pub fn new(registry: Registry, data_dir: PathBuf) -> Self {
+        Self {
+            registry: Arc::new(Mutex::new(registry)),
+            data_dir,
+        }
+    }
+
+    async fn handle(&self, node_id: NodeId, request: HostRequest) -> HostResponse {
+        let mut registry = self.registry.lock().await;
+        let bans = match BanList::load(&self.data_dir) {
+            Ok(bans) => bans,
+            Err(err) => {
+                warn!("Could not load ban list: {err:#}");
+                return HostResponse::Rejected {
+                    reason: "Host unavailable".to_owned(),
+                };
+            }
+        };
+        match request {
+            HostRequest::Register { nickname } => {
+                // Banned nodes cannot register, nor take a banned nickname on a new node
+                if let Some(ban) = bans
+                    .find(&node_id, &registry)
+                    .or_else(|| bans.find_nickname(&nickname))
+                {
+                    info!("Refused registration of {nickname} for banned node {node_id}");
+                    return HostResponse::Rejected {
+                        reason: format!("Banned by the host: {}", ban.reason),
+                    };
+                }
+                match registry.register(node_id, &nickname) {
+                    Ok(_) => {
+                        info!("Registered {nickname} for {node_id}");
+                        HostResponse::Registered
+                    }
+                    Err(err) => HostResponse::Rejected {
+                        reason: err.to_string(),
+                    },
+                }
+            }
+            HostRequest::LookupNode { node_id } => {
+                let registration = registry.by_node_id(&node_id);
+                HostResponse::Registration {
+                    node_id: Some(node_id),
+                    nickname: registration.map(|r| r.nickname.clone()),
+                }
+            }
+            HostRequest::LookupNickname { nickname } => {
+                let registration = registry.by_nickname(&nickname);
+                HostResponse::Registration {
+                    node_id: registration.map(|r| r.node_id),
+                    nickname: registration.map(|r| r.nickname.clone()),
+                }
+            }
+            HostRequest::ListBans => HostResponse::Bans {
+                bans: bans.entries(&registry),
+            },
+            HostRequest::Quit => unreachable!("handled by the connection loop"),
+        }
+    }
+}
+
+impl ProtocolHandler for RegistryProtocol {
+    fn accept(&self, connection: Connection) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            // The node id is authenticated by the QUIC handshake, so a peer can only
+            // ever register a nickname for itself
+            let node_id = connection.remote_node_id()?;
+            trace!("accepted connection from {node_id}");
+            let (mut send, mut recv) = connection.accept_bi().await?;
+
+            loop {
+                let request: HostRequest = match recv_msg(&mut recv).await {
+                    Ok(request) => request,
+                    Err(err) => {
+                        warn!("Closing connection to {node_id}: {err:#}");
+                        break;
+                    }
+                };
+                if let HostRequest::Quit = request {
+                    trace!("Received quit command, closing connection.");
+                    break;
+                }
+                let response = this.handle(node_id, request).await;
+                send_msg(&mut send, &response).await?;
+            }
+            send.finish()?;
+            Ok(())
+        })
+    }
+}
+
+pub async fn recv_msg<T>(recv: &mut RecvStream) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut incoming_len = [0u8; 8];
+    recv.read_exact(&mut incoming_len).await?;
+    let len = u64::from_le_bytes(incoming_len);
+    anyhow::ensure!(
+        len <= MAX_MESSAGE_SIZE,
+        "Message of {len} bytes exceeds the limit of {MAX_MESSAGE_SIZE} bytes"
+    );
+
+    let mut buffer = vec![0u8; len as usize];
+    recv.read_exact(&mut buffer).await?;
+    let msg: T = postcard::from_bytes(&buffer)?;
+    Ok(msg)
+}
+
+pub async fn send_msg<T>(send: &mut SendStream, msg: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    let encoded = postcard::to_stdvec(msg)?;
+    send.write_all(&(encoded.len() as u64).to_le_bytes())
+        .await?;
+    send.write_all(&encoded).await?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REGISTRY_FILE: &str = "registry.json";
+const NICKNAME_MIN_LEN: usize = 3;
+const NICKNAME_MAX_LEN: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    pub node_id: NodeId,
+    pub nickname: String,
+    /// Seconds since the unix epoch.
+    pub registered_at: u64,
+}
+
+/// Persistent mapping between node ids and nicknames.
+///
+/// Every node holds at most one nickname and nicknames are unique ignoring case.
+#[derive(Debug)]
+pub struct Registry {
+    path: PathBuf,
+    registrations: Vec<Registration>,
+}
+
+impl Registry {
+    pub fn load(data_dir: &std::path::Path) -> Result<Self> {
+        let path = data_dir.join(REGISTRY_FILE);
+        let registrations = if path.exists() {
+            let data = std::fs::read(&path)?;
+            serde_json::from_slice(&data).context("Failed to parse registry")?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path,
+            registrations,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.registrations)?;
+        // Write to a temporary file first so a crash never leaves a truncated registry
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path).context("Failed to save registry")?;
+        Ok(())
+    }
+
+    /// Registers `nickname` for `node_id`, replacing the node's previous nickname.
+    pub fn register(&mut self, node_id: NodeId, nickname: &str) -> Result<Registration> {
+        validate_nickname(nickname)?;
+        if let Some(owner) = self.by_nickname(nickname) {
+            anyhow::ensure!(
+                owner.node_id == node_id,
+                "Nickname {nickname} is already taken"
+            );
+        }
+
+        let registration = Registration {
+            node_id,
+            nickname: nickname.to_owned(),
+            registered_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        self.registrations.retain(|r| r.node_id != node_id);
+        self.registrations.push(registration.clone());
+        self.save()?;
+        Ok(registration)
+    }
+
+    pub fn by_node_id(&self, node_id: &NodeId) -> Option<&Registration> {
+        self.registrations.iter().find(|r| &r.node_id == node_id)
+    }
+
+    pub fn by_nickname(&self, nickname: &str) -> Option<&Registration> {
+        self.registrations
+            .iter()
+            .find(|r| r.nickname.eq_ignore_ascii_case(nickname))
+    }
+}
+
+fn validate_nickname(nickname: &str) -> Result<()> {
+    let len = nickname.chars().count();
+    anyhow::ensure!(
+        (NICKNAME_MIN_LEN..=NICKNAME_MAX_LEN).contains(&len),
+        "Nickname must be between {NICKNAME_MIN_LEN} and {NICKNAME_MAX_LEN} characters"
+    );
+    anyhow::ensure!(
+        nickname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')),
+        "Nickname may only contain letters, digits, '_', '-' and '.'"
+    );
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::index::SharedRoot;
+use crate::network::favourites::Favourites;
+
+const ACCESS_FILE: &str = "access.json";
+
+/// Who may list a share and fetch its blobs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Visibility {
+    /// Every peer.
+    #[default]
+    Public,
+    /// Only peers added as favourites.
+    Friends,
+    /// Only the listed nodes.
+    AllowList { nodes: Vec<NodeId> },
+}
+
+/// Visibility of our shares by tag, persisted under `APP_DATA_DIR`. Shares without an
+/// entry are public.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Arc<Mutex<HashMap<String, Visibility>>>,
+    /// Visibility of shares without a rule.
+    default: Visibility,
+    favourites: Favourites,
+}
+
+impl AccessControl {
+    pub fn load(favourites: Favourites) -> Self {
+        let path = crate::global::APP_DATA_DIR.join(ACCESS_FILE);
+        let (rules, default) = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(rules) => (rules, Visibility::Public),
+                Err(err) => {
+                    // Rather hide public shares than expose private ones
+                    warn!("Unreadable share access rules, treating shares as friends only: {err}");
+                    (HashMap::new(), Visibility::Friends)
+                }
+            },
+            Err(_) => (HashMap::new(), Visibility::Public),
+        };
+        Self {
+            rules: Arc::new(Mutex::new(rules)),
+            default,
+            favourites,
+        }
+    }
+
+    pub async fn visibility(&self, tag: &str) -> Visibility {
+        let rules = self.rules.lock().await;
+        rules.get(tag).unwrap_or(&self.default).clone()
+    }
+
+    pub async fn set_visibility(&self, tag: &str, visibility: Visibility) -> Result<()> {
+        let mut rules = self.rules.lock().await;
+        // Unless the rules were unreadable on load, shares without a rule are public
+        if visibility == Visibility::Public && self.default == Visibility::Public {
+            rules.remove(tag);
+        } else {
+            rules.insert(tag.to_owned(), visibility);
+        }
+        save(&rules).await
+    }
+
+    /// Drops the rule of a share that is no longer shared.
+    pub async fn remove(&self, tag: &str) -> Result<()> {
+        let mut rules = self.rules.lock().await;
+        if rules.remove(tag).is_some() {
+            save(&rules).await?;
+        }
+        Ok(())
+    }
+
+    /// The roots `viewer` may see, all of them for ourselves (`None`).
+    pub async fn visible<'a>(
+        &self,
+        roots: impl IntoIterator<Item = &'a SharedRoot>,
+        viewer: Option<NodeId>,
+    ) -> Vec<&'a SharedRoot> {
+        let Some(viewer) = viewer else {
+            return roots.into_iter().collect();
+        };
+        let rules = self.rules.lock().await;
+        roots
+            .into_iter()
+            .filter(|root| match rules.get(&root.tag).unwrap_or(&self.default) {
+                Visibility::Public => true,
+                Visibility::Friends => self.favourites.contains(viewer),
+                Visibility::AllowList { nodes } => nodes.contains(&viewer),
+            })
+            .collect()
+    }
+}
+
+async fn save(rules: &HashMap<String, Visibility>) -> Result<()> {
+    let path = crate::global::APP_DATA_DIR.join(ACCESS_FILE);
+    let data = serde_json::to_vec_pretty(rules)?;
+    tokio::fs::write(&path, data)
+        .await
+        .context("Failed to save share access rules")?;
+    Ok(())
+}
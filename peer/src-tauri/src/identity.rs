@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use iroh::SecretKey;
+use std::io::Write;
+use std::path::Path;
+use tracing::warn;
+
+use crate::global::APP_DATA_DIR;
+
+const SECRET_KEY_FILE: &str = "secret.key";
+const USERNAME_FILE: &str = "username";
+
+/// Loads our secret key, generating one on first start. Keeping the key keeps our
+/// `NodeId` stable across restarts, so peers and the host still recognize us.
+pub fn load_secret_key() -> Result<SecretKey> {
+    let path = APP_DATA_DIR.join(SECRET_KEY_FILE);
+    if path.exists() {
+        #[cfg(unix)]
+        if let Err(err) = restrict_permissions(&path) {
+            warn!("Could not restrict access to {}: {err}", path.display());
+        }
+        let bytes: [u8; 32] = std::fs::read(&path)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid secret key file {}", path.display()))?;
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+    let secret_key = SecretKey::generate(rand::rngs::OsRng);
+    write_secret_key(&path, &secret_key).context("Failed to save secret key")?;
+    Ok(secret_key)
+}
+
+/// Creates the key file readable by us only, whoever reads the key can act as us.
+fn write_secret_key(path: &Path, secret_key: &SecretKey) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&secret_key.to_bytes())
+}
+
+/// Takes read access to a key file saved by an earlier version away from others.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        permissions.set_mode(0o600);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// The username of the previous session, if one was set.
+pub fn load_username() -> Option<String> {
+    let path = APP_DATA_DIR.join(USERNAME_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(username) if !username.trim().is_empty() => Some(username.trim().to_owned()),
+        Ok(_) => None,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            warn!("Could not read saved username: {err}");
+            None
+        }
+    }
+}
+
+pub fn save_username(username: &str) -> Result<()> {
+    std::fs::write(APP_DATA_DIR.join(USERNAME_FILE), username).context("Failed to save username")
+}
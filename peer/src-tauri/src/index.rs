@@ -0,0 +1,579 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh_blobs::Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::network::protocol::TreeNode;
+
+const INDEX_FILE: &str = "index.bin";
+/// Bumped whenever the on-disk format changes, an outdated index is rebuilt from the store.
+const INDEX_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedFile {
+    /// Path inside the collection, `/` separated.
+    pub path: String,
+    pub hash: Hash,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl IndexedFile {
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// A shared file or directory, stored in the blob store as a collection under `tag`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedRoot {
+    /// Tag of the collection, the absolute path that was shared.
+    pub tag: String,
+    /// Hash of the collection.
+    pub hash: Hash,
+    /// Hash of the collection's metadata blob, which names its entries.
+    pub meta: Hash,
+    pub files: Vec<IndexedFile>,
+}
+
+impl SharedRoot {
+    /// The collection, its metadata blob and the files.
+    fn hashes(&self) -> impl Iterator<Item = Hash> + '_ {
+        [self.hash, self.meta]
+            .into_iter()
+            .chain(self.files.iter().map(|file| file.hash))
+    }
+
+    /// Where `file` lives on disk, files are imported by reference and read from there.
+    pub fn source_path(&self, file: &IndexedFile) -> Option<PathBuf> {
+        // Collection entries are named relative to the parent of the shared path
+        Path::new(&self.tag)
+            .parent()
+            .map(|parent| parent.join(&file.path))
+    }
+
+    /// The tree of the root's files, cut off below `depth` levels if given.
+    ///
+    /// Node ids hash the tag with the node's path, so they stay the same as long as
+    /// the share does. Directories carry the collection hash.
+    pub fn tree(&self, depth: Option<usize>) -> Vec<TreeNode> {
+        let mut res = Vec::new();
+        for file in &self.files {
+            let path = PathBuf::from(&file.path);
+            let mut node = &mut res;
+            let len = path.iter().count();
+            let mut cur_path = PathBuf::new();
+            'inner: for (i, p) in path.iter().enumerate() {
+                cur_path.push(p);
+                let absolute_path = self.tag.clone() + "/" + cur_path.to_str().unwrap_or("unknown");
+                let id = Hash::new(&absolute_path);
+
+                let child_index = node.iter().position(|c: &TreeNode| c.id == id.to_string());
+
+                if let Some(idx) = child_index {
+                    node = node[idx]
+                        .children
+                        .as_mut()
+                        .expect("Node should have children");
+                } else {
+                    let (children, node_hash) = if i == len - 1 {
+                        (None, file.hash)
+                    } else {
+                        (Some(Vec::new()), self.hash)
+                    };
+                    let new_node = TreeNode {
+                        id: id.to_string(),
+                        name: p.to_string_lossy().to_string(),
+                        hash: node_hash.to_string(),
+                        path: cur_path.to_string_lossy().to_string(),
+                        size: Some(file.size),
+                        children,
+                        modified: file.modified,
+                    };
+                    node.push(new_node);
+                    if i == len - 1 {
+                        break 'inner;
+                    }
+                    node = node
+                        .last_mut()
+                        .expect("Should exist see above line")
+                        .children
+                        .as_mut()
+                        .expect("Node should have children");
+                }
+                if let Some(max_depth) = depth {
+                    if i >= max_depth {
+                        break 'inner;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+/// The tree of one root split up by directory, so a page of a directory can be served
+/// without building the tree. Directories are listed with empty `children`.
+#[derive(Debug, Default)]
+struct Listing {
+    top: Vec<TreeNode>,
+    /// Direct children of every directory by node id.
+    dirs: HashMap<String, Vec<TreeNode>>,
+}
+
+impl Listing {
+    fn new(root: &SharedRoot) -> Self {
+        let mut dirs = HashMap::new();
+        let top = flatten(root.tree(None), &mut dirs);
+        Self { top, dirs }
+    }
+}
+
+/// Moves the children of `nodes` into `dirs`, returns `nodes` in listing order.
+fn flatten(mut nodes: Vec<TreeNode>, dirs: &mut HashMap<String, Vec<TreeNode>>) -> Vec<TreeNode> {
+    for node in &mut nodes {
+        if let Some(children) = node.children.as_mut() {
+            let children = flatten(std::mem::take(children), dirs);
+            dirs.insert(node.id.clone(), children);
+        }
+    }
+    sort_listing(&mut nodes);
+    nodes
+}
+
+/// Directories first, then files, each sorted by name.
+fn sort_listing(nodes: &mut [impl std::borrow::Borrow<TreeNode>]) {
+    nodes.sort_by(|a, b| {
+        let (a, b): (&TreeNode, &TreeNode) = (a.borrow(), b.borrow());
+        b.children
+            .is_some()
+            .cmp(&a.children.is_some())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexData {
+    version: u32,
+    roots: Vec<SharedRoot>,
+}
+
+/// Persistent index of everything we share, so listings and searches do not have to
+/// walk the blob store.
+///
+/// File names are split into lowercase alphanumeric tokens, a query matches a file
+/// when every query token is a prefix of one of the file name's tokens. Tokens and
+/// listings are kept per root, so changing a root leaves the others alone.
+///
+/// Blob requests look up the roots holding a hash, and the ticket collections created
+/// for the roots, which are not saved with the index but rebuilt from the store tags.
+#[derive(Debug, Default)]
+pub struct FileIndex {
+    roots: Vec<SharedRoot>,
+    /// Tokens of every root by tag.
+    tokens: HashMap<String, Tokens>,
+    /// Listing of every root by tag.
+    listings: HashMap<String, Listing>,
+    /// Tags of the roots holding a hash as their collection, metadata blob or file.
+    holders: HashMap<Hash, Vec<String>>,
+    /// Tag of the root each ticket collection and ticket metadata blob was created for.
+    tickets: HashMap<Hash, String>,
+}
+
+impl FileIndex {
+    pub fn new(roots: Vec<SharedRoot>) -> Self {
+        let mut index = Self::default();
+        for root in roots {
+            index.upsert(root);
+        }
+        index
+    }
+
+    /// Loads the index saved by a previous session, `None` if there is no usable index.
+    pub fn load() -> Option<Self> {
+        let path = crate::global::APP_DATA_DIR.join(INDEX_FILE);
+        let data = std::fs::read(&path).ok()?;
+        match postcard::from_bytes::<IndexData>(&data) {
+            Ok(data) if data.version == INDEX_VERSION => Some(Self::new(data.roots)),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Discarding unreadable file index: {err}");
+                None
+            }
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let path = crate::global::APP_DATA_DIR.join(INDEX_FILE);
+        let data = postcard::to_stdvec(&IndexData {
+            version: INDEX_VERSION,
+            roots: self.roots.clone(),
+        })?;
+        tokio::fs::write(&path, data)
+            .await
+            .context("Failed to save file index")?;
+        Ok(())
+    }
+
+    /// Whether the index holds exactly the shares `tags`, with the same collections.
+    pub fn matches(&self, tags: &BTreeMap<String, Hash>) -> bool {
+        self.roots.len() == tags.len()
+            && self
+                .roots
+                .iter()
+                .all(|root| tags.get(&root.tag) == Some(&root.hash))
+    }
+
+    pub fn roots(&self) -> &[SharedRoot] {
+        &self.roots
+    }
+
+    /// Adds a root, replacing any root shared under the same tag. Tickets of the
+    /// replaced root stay, they still hold files of the share.
+    pub fn upsert(&mut self, root: SharedRoot) {
+        if let Some(position) = self.roots.iter().position(|r| r.tag == root.tag) {
+            let previous = self.roots.remove(position);
+            self.remove_holders(&previous);
+        }
+        self.listings.insert(root.tag.clone(), Listing::new(&root));
+        self.tokens.insert(root.tag.clone(), tokens(&root));
+        for hash in root.hashes() {
+            let holders = self.holders.entry(hash).or_default();
+            if !holders.contains(&root.tag) {
+                holders.push(root.tag.clone());
+            }
+        }
+        self.roots.push(root);
+    }
+
+    /// Removes a root together with its tickets.
+    pub fn remove(&mut self, tag: &str) -> Option<SharedRoot> {
+        let position = self.roots.iter().position(|r| r.tag == tag)?;
+        let root = self.roots.remove(position);
+        self.listings.remove(tag);
+        self.tokens.remove(tag);
+        self.remove_holders(&root);
+        self.tickets.retain(|_, share| share != tag);
+        Some(root)
+    }
+
+    pub fn clear(&mut self) {
+        self.roots.clear();
+        self.tokens.clear();
+        self.listings.clear();
+        self.holders.clear();
+        self.tickets.clear();
+    }
+
+    fn remove_holders(&mut self, root: &SharedRoot) {
+        for hash in root.hashes() {
+            if let Some(holders) = self.holders.get_mut(&hash) {
+                holders.retain(|tag| tag != &root.tag);
+                if holders.is_empty() {
+                    self.holders.remove(&hash);
+                }
+            }
+        }
+    }
+
+    /// The roots holding `hash` as their collection, metadata blob or file.
+    pub fn holding(&self, hash: &Hash) -> Vec<&SharedRoot> {
+        let Some(tags) = self.holders.get(hash) else {
+            return Vec::new();
+        };
+        self.roots
+            .iter()
+            .filter(|root| tags.contains(&root.tag))
+            .collect()
+    }
+
+    /// Records the ticket collection `hash` with metadata blob `meta` created for the
+    /// root `tag`.
+    pub fn add_ticket(&mut self, tag: &str, hash: Hash, meta: Hash) {
+        self.tickets.insert(hash, tag.to_owned());
+        self.tickets.insert(meta, tag.to_owned());
+    }
+
+    /// Whether `hash` is a ticket collection or the metadata blob of one.
+    pub fn is_ticket(&self, hash: &Hash) -> bool {
+        self.tickets.contains_key(hash)
+    }
+
+    /// The direct children of the directory with id `parent`, or the top level nodes
+    /// for `None`, in the roots `include` accepts. Directories come first, then files,
+    /// each sorted by name. `None` if there is no such directory.
+    pub fn children(
+        &self,
+        parent: Option<&str>,
+        include: impl Fn(&SharedRoot) -> bool,
+    ) -> Option<Vec<&TreeNode>> {
+        let listings = self
+            .roots
+            .iter()
+            .filter(|root| include(root))
+            .filter_map(|root| self.listings.get(&root.tag));
+        match parent {
+            None => {
+                let mut top: Vec<&TreeNode> =
+                    listings.flat_map(|listing| listing.top.iter()).collect();
+                sort_listing(&mut top);
+                Some(top)
+            }
+            Some(id) => listings
+                .find_map(|listing| listing.dirs.get(id))
+                .map(|children| children.iter().collect()),
+        }
+    }
+
+    /// Finds the root that is, or contains a file with, the given hash.
+    pub fn root_containing(&self, hash: &str) -> Option<&SharedRoot> {
+        self.roots.iter().find(|root| {
+            root.hash.to_string() == hash || root.files.iter().any(|f| f.hash.to_string() == hash)
+        })
+    }
+
+    /// Returns up to `limit` files matching `query` in the roots `include` accepts,
+    /// ordered by path.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        include: impl Fn(&SharedRoot) -> bool,
+    ) -> Vec<&IndexedFile> {
+        let query: Vec<String> = tokenize(query).collect();
+        let mut files: Vec<&IndexedFile> = self
+            .roots
+            .iter()
+            .filter(|root| include(root))
+            .filter_map(|root| Some((root, self.tokens.get(&root.tag)?)))
+            .flat_map(|(root, tokens)| {
+                matching(tokens, &query)
+                    .into_iter()
+                    .map(|file| &root.files[file])
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files.truncate(limit);
+        files
+    }
+}
+
+/// Token -> indexes of the files of one root whose names have the token.
+type Tokens = BTreeMap<String, Vec<usize>>;
+
+fn tokens(root: &SharedRoot) -> Tokens {
+    let mut tokens = Tokens::new();
+    for (file_index, file) in root.files.iter().enumerate() {
+        for token in tokenize(file.name()) {
+            tokens.entry(token).or_default().push(file_index);
+        }
+    }
+    tokens
+}
+
+/// The files with a name token starting with every token of `query`, none for an
+/// empty query.
+fn matching(tokens: &Tokens, query: &[String]) -> HashSet<usize> {
+    let mut matches: Option<HashSet<usize>> = None;
+    for token in query {
+        let found: HashSet<usize> = tokens
+            .range(token.clone()..)
+            .take_while(|(key, _)| key.starts_with(token.as_str()))
+            .flat_map(|(_, files)| files.iter().copied())
+            .collect();
+        matches = Some(match matches {
+            Some(previous) => previous.intersection(&found).copied().collect(),
+            None => found,
+        });
+    }
+    matches.unwrap_or_default()
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, n: u8) -> IndexedFile {
+        IndexedFile {
+            path: path.to_owned(),
+            hash: Hash::new([n]),
+            size: n as u64,
+            modified: None,
+        }
+    }
+
+    fn root(tag: &str, files: Vec<IndexedFile>) -> SharedRoot {
+        SharedRoot {
+            tag: tag.to_owned(),
+            hash: Hash::new(tag),
+            meta: Hash::new(format!("{tag}/meta")),
+            files,
+        }
+    }
+
+    fn names(nodes: &[&TreeNode]) -> Vec<String> {
+        nodes.iter().map(|node| node.name.clone()).collect()
+    }
+
+    #[test]
+    fn lists_directories_first_then_files() {
+        let index = FileIndex::new(vec![root(
+            "/home/me/music",
+            vec![
+                file("music/b.mp3", 1),
+                file("music/live/c.mp3", 2),
+                file("music/a.mp3", 3),
+                file("music/covers/d.mp3", 4),
+            ],
+        )]);
+        let top = index.children(None, |_| true).unwrap();
+        assert_eq!(names(&top), ["music"]);
+        assert!(top[0].children.as_ref().is_some_and(Vec::is_empty));
+
+        let children = index.children(Some(&top[0].id), |_| true).unwrap();
+        assert_eq!(names(&children), ["covers", "live", "a.mp3", "b.mp3"]);
+
+        let live = index.children(Some(&children[1].id), |_| true).unwrap();
+        assert_eq!(names(&live), ["c.mp3"]);
+        assert!(index.children(Some(&live[0].id), |_| true).is_none());
+    }
+
+    #[test]
+    fn lists_only_included_roots() {
+        let mut index = FileIndex::new(vec![
+            root("/a/docs", vec![file("docs/x.txt", 1)]),
+            root("/b/photos", vec![file("photos/y.jpg", 2)]),
+        ]);
+        let top = index.children(None, |_| true).unwrap();
+        assert_eq!(names(&top), ["docs", "photos"]);
+        let photos = top[1].id.clone();
+
+        let top = index.children(None, |root| root.tag == "/a/docs").unwrap();
+        assert_eq!(names(&top), ["docs"]);
+        assert!(index
+            .children(Some(&photos), |root| root.tag == "/a/docs")
+            .is_none());
+
+        index.remove("/b/photos");
+        assert!(index.children(Some(&photos), |_| true).is_none());
+    }
+
+    fn paths(files: &[&IndexedFile]) -> Vec<String> {
+        files.iter().map(|file| file.path.clone()).collect()
+    }
+
+    #[test]
+    fn search_matches_every_token_by_prefix() {
+        let index = FileIndex::new(vec![root(
+            "/home/me/music",
+            vec![
+                file("music/Daft Punk - Around the World.mp3", 1),
+                file("music/Daft Punk - One More Time.flac", 2),
+                file("music/worldwide.mp3", 3),
+            ],
+        )]);
+        let found = index.search("daft wor", 10, |_| true);
+        assert_eq!(paths(&found), ["music/Daft Punk - Around the World.mp3"]);
+
+        let found = index.search("WORLD", 10, |_| true);
+        assert_eq!(
+            paths(&found),
+            [
+                "music/Daft Punk - Around the World.mp3",
+                "music/worldwide.mp3"
+            ]
+        );
+        assert!(index.search("", 10, |_| true).is_empty());
+        assert!(index.search("daft jazz", 10, |_| true).is_empty());
+    }
+
+    #[test]
+    fn search_follows_upserts_and_removals() {
+        let mut index = FileIndex::new(vec![
+            root("/a/docs", vec![file("docs/report.pdf", 1)]),
+            root("/b/photos", vec![file("photos/report.jpg", 2)]),
+        ]);
+        assert_eq!(index.search("report", 1, |_| true).len(), 1);
+        let found = index.search("report", 10, |root| root.tag == "/b/photos");
+        assert_eq!(paths(&found), ["photos/report.jpg"]);
+
+        index.remove("/a/docs");
+        let found = index.search("report", 10, |_| true);
+        assert_eq!(paths(&found), ["photos/report.jpg"]);
+
+        index.upsert(root("/b/photos", vec![file("photos/holiday.jpg", 3)]));
+        assert!(index.search("report", 10, |_| true).is_empty());
+        let found = index.search("holi", 10, |_| true);
+        assert_eq!(paths(&found), ["photos/holiday.jpg"]);
+    }
+
+    #[test]
+    fn matches_the_store_tags() {
+        let index = FileIndex::new(vec![root("/a/docs", vec![file("docs/x.txt", 1)])]);
+        let mut tags = BTreeMap::from([("/a/docs".to_owned(), Hash::new("/a/docs"))]);
+        assert!(index.matches(&tags));
+
+        tags.insert("/b/photos".to_owned(), Hash::new("/b/photos"));
+        assert!(!index.matches(&tags));
+        tags.remove("/b/photos");
+        tags.insert("/a/docs".to_owned(), Hash::new([1]));
+        assert!(!index.matches(&tags));
+    }
+
+    #[test]
+    fn finds_the_roots_holding_a_hash() {
+        let mut index = FileIndex::new(vec![
+            root(
+                "/a/docs",
+                vec![file("docs/x.txt", 1), file("docs/y.txt", 2)],
+            ),
+            root("/b/more", vec![file("more/x.txt", 1)]),
+        ]);
+        let tags = |index: &FileIndex, hash: &Hash| -> Vec<String> {
+            let mut tags: Vec<String> = index
+                .holding(hash)
+                .iter()
+                .map(|root| root.tag.clone())
+                .collect();
+            tags.sort();
+            tags
+        };
+        assert_eq!(tags(&index, &Hash::new([1])), ["/a/docs", "/b/more"]);
+        assert_eq!(tags(&index, &Hash::new([2])), ["/a/docs"]);
+        assert_eq!(tags(&index, &Hash::new("/a/docs")), ["/a/docs"]);
+        assert_eq!(tags(&index, &Hash::new("/a/docs/meta")), ["/a/docs"]);
+        assert!(tags(&index, &Hash::new([3])).is_empty());
+
+        index.upsert(root("/a/docs", vec![file("docs/z.txt", 3)]));
+        assert_eq!(tags(&index, &Hash::new([1])), ["/b/more"]);
+        assert!(tags(&index, &Hash::new([2])).is_empty());
+        assert_eq!(tags(&index, &Hash::new([3])), ["/a/docs"]);
+
+        index.remove("/b/more");
+        assert!(tags(&index, &Hash::new([1])).is_empty());
+    }
+
+    #[test]
+    fn tickets_go_with_their_root() {
+        let mut index = FileIndex::new(vec![root("/a/docs", vec![file("docs/x.txt", 1)])]);
+        index.add_ticket("/a/docs", Hash::new("ticket"), Hash::new("ticket/meta"));
+        assert!(index.is_ticket(&Hash::new("ticket")));
+        assert!(index.is_ticket(&Hash::new("ticket/meta")));
+        assert!(!index.is_ticket(&Hash::new([1])));
+
+        index.upsert(root("/a/docs", vec![file("docs/x.txt", 1)]));
+        assert!(index.is_ticket(&Hash::new("ticket")));
+        index.remove("/a/docs");
+        assert!(!index.is_ticket(&Hash::new("ticket")));
+        assert!(!index.is_ticket(&Hash::new("ticket/meta")));
+    }
+}
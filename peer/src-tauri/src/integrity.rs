@@ -0,0 +1,112 @@
+use chrono::{DateTime, Utc};
+use iroh_blobs::Hash;
+use serde::Serialize;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tracing::{info, warn};
+
+use crate::index::{IndexedFile, SharedRoot};
+use crate::network::protocol::FileProtocol;
+
+/// How often the background checker looks at the files behind our shares.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceStatus {
+    /// The file no longer exists where it was shared from.
+    Missing,
+    /// The file's content differs from when it was shared.
+    Changed,
+}
+
+/// A shared file whose source on disk no longer matches what we share.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShareIssue {
+    /// Tag of the share the file belongs to.
+    pub tag: String,
+    /// Hash the file was shared with.
+    pub hash: String,
+    pub path: PathBuf,
+    pub status: SourceStatus,
+}
+
+/// Checks every file of `roots` against its source on disk. Blocking.
+pub fn check_roots(roots: &[SharedRoot], deep: bool) -> Vec<ShareIssue> {
+    let mut issues = Vec::new();
+    for root in roots {
+        for file in &root.files {
+            let Some(path) = root.source_path(file) else {
+                continue;
+            };
+            if let Some(status) = check_file(&path, file, deep) {
+                issues.push(ShareIssue {
+                    tag: root.tag.clone(),
+                    hash: file.hash.to_string(),
+                    path,
+                    status,
+                });
+            }
+        }
+    }
+    issues
+}
+
+fn check_file(path: &Path, file: &IndexedFile, deep: bool) -> Option<SourceStatus> {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Some(SourceStatus::Missing);
+    };
+    let modified: Option<DateTime<Utc>> = metadata.modified().ok().map(DateTime::from);
+    let touched = matches!((file.modified, modified), (Some(shared), Some(current)) if shared != current);
+    if metadata.len() != file.size || touched {
+        return Some(SourceStatus::Changed);
+    }
+    if deep {
+        match hash_file(path) {
+            Ok(hash) if hash == file.hash => {}
+            Ok(_) => return Some(SourceStatus::Changed),
+            Err(err) => {
+                warn!("Could not hash {}: {err}", path.display());
+                return Some(SourceStatus::Missing);
+            }
+        }
+    }
+    None
+}
+
+fn hash_file(path: &Path) -> std::io::Result<Hash> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(Hash::from(hasher.finalize()))
+}
+
+/// Periodically checks the sources of our shares, emitting `share::issues` with the
+/// full list of stale files whenever it changes.
+pub fn spawn_checker(file_protocol: FileProtocol, app: AppHandle) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut previous: Vec<ShareIssue> = Vec::new();
+        loop {
+            interval.tick().await;
+            let issues = file_protocol.check_sources(false).await;
+            if issues == previous {
+                continue;
+            }
+            if !issues.is_empty() {
+                info!("{} shared files are missing or changed", issues.len());
+            }
+            let _ = app.emit("share::issues", &issues);
+            previous = issues;
+        }
+    })
+}
@@ -1,10 +1,38 @@
+mod access;
 mod global;
+mod identity;
+mod index;
+mod integrity;
 mod network;
+mod settings;
 mod state;
 mod utils;
+mod watcher;
+use access::Visibility;
+use integrity::ShareIssue;
 use iroh::NodeId;
-use network::protocol::{client::list_remote_files, TreeNode};
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use iroh_blobs::ticket::BlobTicket;
+use iroh_blobs::BlobFormat;
+use iroh_docs::NamespaceId;
+use network::host::{self, HOST_ADDR};
+use network::bandwidth::BandwidthStats;
+use network::chat::{self, ChatEvent, DeliveryStatus, Direction, StoredMessage};
+use network::moderation::Banned;
+use network::outbox::Outbox;
+use network::protocol::{
+    client::{self, list_remote_children, list_remote_files},
+    ChildrenPage, TreeNode,
+};
+use network::queue::Transfer;
+use network::rooms::{RoomInfo, RoomManager, RoomMessage};
+use network::search::SearchGroup;
+use network::slots::{SlotStatus, SlotsSnapshot};
+use network::swarm::{self, SwarmContext};
+use network::ticket;
+use network::transfer::{self, ProgressReporter};
+use settings::{BandwidthSettings, Settings};
+use watcher::ShareSummary;
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, instrument, trace, warn};
 
@@ -23,10 +51,13 @@ async fn add_path(path: String, state: tauri::State<'_, AppStateWrapper>) -> Res
     if !path.exists() {
         return Err("Path does not exist".to_string());
     }
-    state
+    let file_protocol = state
         .file_protocol
         .clone()
-        .ok_or("File protocol not initialized")?
+        .ok_or("File protocol not initialized")?;
+    // Hashing large folders takes a while, do not hold the state lock meanwhile
+    drop(state);
+    file_protocol
         .import(path)
         .await
         .map_err(|err| err.to_string())?;
@@ -45,7 +76,7 @@ async fn get_uploaded_files_tree(
         .ok_or("File protocol not initialized")?;
 
     file_protocol
-        .get_files_tree(None)
+        .get_files_tree(None, None)
         .await
         .map_err(|e| format!("Failed to get files tree: {}", e))
 }
@@ -57,18 +88,211 @@ async fn ping_peer(
     state: tauri::State<'_, AppStateWrapper>,
 ) -> Result<(), String> {
     let state = state.0.lock().await;
-    if state.router.is_none() {
-        return Err("Endpoint not initialized".to_string());
-    }
-    let endpoint = state.router.clone().unwrap().endpoint().clone();
+    let sessions = state.sessions.clone().ok_or("Endpoint not initialized")?;
     let node_id =
         iroh::NodeId::from_str(peer_id.as_str()).map_err(|_| "Invalid node ID".to_string())?;
     let node_addr = state.get_node_addr(node_id).await.map_err(|err| err.to_string())?;
-    crate::network::protocol::client::ping_peer(&endpoint, node_addr)
+    drop(state);
+    crate::network::protocol::client::ping_peer(&sessions, node_addr)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state, text), ret, err)]
+#[tauri::command]
+async fn send_message(
+    node_id: String,
+    text: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<StoredMessage, String> {
+    let text = text.trim().to_owned();
+    if text.is_empty() {
+        return Err("Message is empty".to_string());
+    }
+    if text.len() > chat::MAX_MESSAGE_LEN {
+        return Err(format!("Message exceeds {} bytes", chat::MAX_MESSAGE_LEN));
+    }
+    let node_id = NodeId::from_str(&node_id).map_err(|_| "Invalid node ID".to_string())?;
+    let outbox = outbox(&state).await?;
+    // Messages to peers that are offline stay queued until they come online
+    outbox
+        .send(node_id, text)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+async fn outbox(state: &tauri::State<'_, AppStateWrapper>) -> Result<Outbox, String> {
+    let state = state.0.lock().await;
+    state
+        .outbox
+        .clone()
+        .ok_or_else(|| "Outbox not initialized".to_string())
+}
+
+#[instrument(skip(state), err)]
+#[tauri::command]
+async fn get_conversation(
+    node_id: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<Vec<StoredMessage>, String> {
+    let node_id = NodeId::from_str(&node_id).map_err(|_| "Invalid node ID".to_string())?;
+    let outbox = outbox(&state).await?;
+    Ok(outbox.history().conversation(node_id).await)
+}
+
+#[instrument(skip_all, err)]
+#[tauri::command]
+async fn get_outbox(state: tauri::State<'_, AppStateWrapper>) -> Result<Vec<ChatEvent>, String> {
+    let outbox = outbox(&state).await?;
+    Ok(outbox.history().outbox().await)
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_message_status(
+    node_id: String,
+    message_id: u64,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<DeliveryStatus, String> {
+    let node_id = NodeId::from_str(&node_id).map_err(|_| "Invalid node ID".to_string())?;
+    let outbox = outbox(&state).await?;
+    outbox
+        .history()
+        .message(node_id, message_id, Direction::Outgoing)
+        .await
+        .map(|message| message.status)
+        .ok_or_else(|| "Unknown message".to_string())
+}
+
+/// Clones the room manager out of the state, so the lock is not held while talking to
+/// the docs store.
+async fn room_manager(state: &tauri::State<'_, AppStateWrapper>) -> Result<RoomManager, String> {
+    let state = state.0.lock().await;
+    state
+        .rooms
+        .clone()
+        .ok_or_else(|| "Rooms not initialized".to_string())
+}
+
+fn parse_room_id(room_id: &str) -> Result<NamespaceId, String> {
+    NamespaceId::from_str(room_id).map_err(|_| "Invalid room ID".to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn create_room(
+    name: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<RoomInfo, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Room name is empty".to_string());
+    }
+    let rooms = room_manager(&state).await?;
+    rooms.create(name).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn join_room(
+    ticket: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<RoomInfo, String> {
+    let rooms = room_manager(&state).await?;
+    rooms.join(&ticket).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn leave_room(
+    room_id: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<(), String> {
+    let room_id = parse_room_id(&room_id)?;
+    let rooms = room_manager(&state).await?;
+    rooms.leave(room_id).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip_all, ret, err)]
+#[tauri::command]
+async fn list_rooms(state: tauri::State<'_, AppStateWrapper>) -> Result<Vec<RoomInfo>, String> {
+    let rooms = room_manager(&state).await?;
+    rooms.list().await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_room_ticket(
+    room_id: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<String, String> {
+    let room_id = parse_room_id(&room_id)?;
+    let rooms = room_manager(&state).await?;
+    rooms
+        .ticket(room_id)
         .await
+        .map(|ticket| ticket.to_string())
         .map_err(|err| err.to_string())
 }
 
+#[instrument(skip(state), err)]
+#[tauri::command]
+async fn get_room_messages(
+    room_id: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<Vec<RoomMessage>, String> {
+    let room_id = parse_room_id(&room_id)?;
+    let rooms = room_manager(&state).await?;
+    rooms.messages(room_id).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state, text), ret, err)]
+#[tauri::command]
+async fn send_room_message(
+    room_id: String,
+    text: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<RoomMessage, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Message is empty".to_string());
+    }
+    let room_id = parse_room_id(&room_id)?;
+    let username = state
+        .0
+        .lock()
+        .await
+        .get_username()
+        .clone()
+        .ok_or("Username not set")?;
+    let rooms = room_manager(&state).await?;
+    rooms
+        .send(room_id, &username, text)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Starts the endpoint if needed and announces `username` on the network.
+async fn go_online(state: &Mutex<AppState>, app: tauri::AppHandle, username: String) -> Result<()> {
+    let mut state = state.lock().await;
+    if state.router.is_none() {
+        state.spawn_endpoint(app.clone()).await?;
+    }
+    state.update_username(username.clone())?;
+    state.start_discovery(app); // TODO Move this to a better place
+    let endpoint = state.router.as_ref().unwrap().endpoint().clone();
+    drop(state);
+
+    // Without a registration other peers see our username as unverified, which
+    // should not keep us off the network
+    if HOST_ADDR.is_some() {
+        if let Err(err) = host::register(&endpoint, &username).await {
+            warn!("Could not register {username} with the host: {err:#}");
+        }
+    }
+    Ok(())
+}
+
 #[instrument(skip(state, app), ret, err)]
 #[tauri::command]
 async fn set_username(
@@ -76,18 +300,108 @@ async fn set_username(
     state: tauri::State<'_, AppStateWrapper>,
     app: tauri::AppHandle,
 ) -> Result<(), String> {
-    let mut state = state.0.lock().await;
-    if state.router.is_none() {
-        state
-            .spawn_endpoint(app.clone())
-            .await
-            .map_err(|err| err.to_string())?;
+    go_online(&state.0, app, username)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip_all, ret, err)]
+#[tauri::command]
+async fn check_shares(
+    deep: Option<bool>,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<Vec<ShareIssue>, String> {
+    let state = state.0.lock().await;
+    let file_protocol = state
+        .file_protocol
+        .clone()
+        .ok_or("File protocol not initialized")?;
+    drop(state);
+    Ok(file_protocol.check_sources(deep.unwrap_or(false)).await)
+}
+
+#[instrument(skip_all, ret, err)]
+#[tauri::command]
+async fn list_shares(
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<Vec<ShareSummary>, String> {
+    let state = state.0.lock().await;
+    let file_protocol = state
+        .file_protocol
+        .clone()
+        .ok_or("File protocol not initialized")?;
+    let watcher = state.watcher.clone().ok_or("Share watcher not initialized")?;
+    drop(state);
+    let watched = watcher.watched().await;
+    let mut shares = Vec::new();
+    for tag in file_protocol.shared_tags().await {
+        shares.push(ShareSummary {
+            watched: watched.contains(&tag),
+            visibility: file_protocol.access().visibility(&tag).await,
+            tag,
+        });
     }
-    state
-        .update_username(username)
-        .map_err(|err| err.to_string())?;
-    state.start_discovery(app); // TODO Move this to a better place
-    Ok(())
+    Ok(shares)
+}
+
+/// Sets who may list the share under `tag` and fetch its files.
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn set_share_visibility(
+    tag: String,
+    visibility: Visibility,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<(), String> {
+    let state = state.0.lock().await;
+    let file_protocol = state
+        .file_protocol
+        .clone()
+        .ok_or("File protocol not initialized")?;
+    drop(state);
+    if !file_protocol.shared_tags().await.contains(&tag) {
+        return Err(format!("{tag} is not shared"));
+    }
+    file_protocol
+        .access()
+        .set_visibility(&tag, visibility)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn set_share_watch(
+    tag: String,
+    enabled: bool,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<(), String> {
+    let state = state.0.lock().await;
+    let watcher = state.watcher.clone().ok_or("Share watcher not initialized")?;
+    drop(state);
+    watcher
+        .set_watched(&tag, enabled)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn rescan_share(
+    tag: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<String, String> {
+    let state = state.0.lock().await;
+    let file_protocol = state
+        .file_protocol
+        .clone()
+        .ok_or("File protocol not initialized")?;
+    // Rescanning hashes the changed files again, do not hold the state lock meanwhile
+    drop(state);
+    file_protocol
+        .rescan(&tag)
+        .await
+        .map(|hash| hash.to_string())
+        .map_err(|err| err.to_string())
 }
 
 #[instrument(skip_all, ret, err)]
@@ -100,25 +414,310 @@ async fn get_username(state: tauri::State<'_, AppStateWrapper>) -> Result<String
         .ok_or_else(|| "Username not set".to_string())
 }
 
+/// Why the host banned us, `None` if we are not banned.
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_ban_status(
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<Option<Banned>, String> {
+    let state = state.0.lock().await;
+    let Some(moderation) = state.moderation.clone() else {
+        return Ok(None);
+    };
+    drop(state);
+    Ok(moderation.own_ban().await)
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_settings(state: tauri::State<'_, AppStateWrapper>) -> Result<Settings, String> {
+    let state = state.0.lock().await;
+    let settings = state.settings.lock().await.clone();
+    Ok(settings)
+}
+
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn update_settings(
+    settings: Settings,
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+) -> Result<Settings, String> {
+    let state = state.0.lock().await;
+    let shared = Arc::clone(&state.settings);
+    drop(state);
+    settings::update(&shared, &app, settings)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_bandwidth_stats(
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<BandwidthStats, String> {
+    let state = state.0.lock().await;
+    let bandwidth = state.bandwidth.clone().ok_or("Endpoint not initialized")?;
+    drop(state);
+    Ok(bandwidth.stats().await)
+}
+
+/// Replaces the bandwidth limits, running transfers pick them up right away.
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn set_bandwidth_limits(
+    limits: BandwidthSettings,
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+) -> Result<Settings, String> {
+    let state = state.0.lock().await;
+    let shared = Arc::clone(&state.settings);
+    drop(state);
+    let mut settings = shared.lock().await.clone();
+    settings.bandwidth = limits;
+    settings::update(&shared, &app, settings)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_upload_slots(state: tauri::State<'_, AppStateWrapper>) -> Result<SlotsSnapshot, String> {
+    let state = state.0.lock().await;
+    let slots = state.slots.as_ref().ok_or("Endpoint not initialized")?;
+    Ok(slots.snapshot())
+}
+
+/// Asks `node_id` where we stand with its upload slots. Peers that support it also
+/// report changes while we wait, as `slots::status` events.
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_slot_status(
+    state: tauri::State<'_, AppStateWrapper>,
+    node_id: NodeId,
+) -> Result<SlotStatus, String> {
+    let state = state.0.lock().await;
+    let sessions = state.sessions.clone().ok_or("Endpoint not initialized")?;
+    let node_addr = state.get_node_addr(node_id).await.map_err(|err| err.to_string())?;
+    drop(state);
+    client::slot_status(&sessions, node_addr)
+        .await
+        .map_err(|err| err.to_string())
+}
+
 #[instrument(skip(state), ret, err)]
 #[tauri::command]
 async fn get_remote_files(
     state: tauri::State<'_, AppStateWrapper>,
     node_id: NodeId,
 ) -> Result<Vec<TreeNode>, String> {
+    let state = state.0.lock().await;
+    let sessions = state.sessions.clone().ok_or("Endpoint not initialized")?;
+    let node_addr = state.get_node_addr(node_id).await.map_err(|err| err.to_string())?;
+    drop(state);
+    list_remote_files(&sessions, node_addr, None)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_remote_children(
+    state: tauri::State<'_, AppStateWrapper>,
+    node_id: NodeId,
+    parent: Option<String>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<ChildrenPage, String> {
+    let state = state.0.lock().await;
+    let sessions = state.sessions.clone().ok_or("Endpoint not initialized")?;
+    let node_addr = state.get_node_addr(node_id).await.map_err(|err| err.to_string())?;
+    drop(state);
+    list_remote_children(&sessions, node_addr, parent, cursor.unwrap_or(0), limit.unwrap_or(200))
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn search(
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+    query: String,
+) -> Result<Vec<SearchGroup>, String> {
+    let query = query.trim().to_owned();
+    if query.is_empty() {
+        return Err("Search query is empty".to_string());
+    }
+    let state = state.0.lock().await;
+    let sessions = state.sessions.clone().ok_or("Endpoint not initialized")?;
+    let peers = state.peers.lock().await.clone();
+    drop(state);
+    Ok(network::search::search_peers(&sessions, peers, query, app).await)
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn download(
+    state: tauri::State<'_, AppStateWrapper>,
+    node_id: NodeId,
+    node: TreeNode,
+    destination: String,
+) -> Result<Transfer, String> {
+    let state = state.0.lock().await;
+    let transfers = state
+        .transfers
+        .clone()
+        .ok_or("Transfer manager not initialized")?;
+    drop(state);
+    transfers
+        .enqueue(node_id, node, PathBuf::from(destination))
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn swarm_download(
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+    hash: String,
+    name: String,
+    destination: String,
+) -> Result<(), String> {
+    let hash = iroh_blobs::Hash::from_str(&hash).map_err(|_| "Invalid hash".to_string())?;
+    let state = state.0.lock().await;
+    let blobs = state.blobs.clone().ok_or("Endpoint not initialized")?;
+    let ctx = SwarmContext {
+        endpoint: blobs.endpoint().clone(),
+        sessions: state.sessions.clone().ok_or("Endpoint not initialized")?,
+        store: blobs.store().clone(),
+        blobs_client: blobs.client().clone(),
+        peers: state.peers.lock().await.clone(),
+        bandwidth: state.bandwidth.clone().ok_or("Endpoint not initialized")?,
+        app_handle: app.clone(),
+    };
+    drop(state);
+
+    let mut progress = ProgressReporter::new(app, name.clone());
+    let result = swarm::swarm_download(ctx, hash, &name, &PathBuf::from(destination), &mut progress).await;
+    match &result {
+        Ok(()) => progress.completed(),
+        Err(err) => progress.failed(err),
+    }
+    result.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn create_share_ticket(
+    state: tauri::State<'_, AppStateWrapper>,
+    node: TreeNode,
+) -> Result<String, String> {
     let state = state.0.lock().await;
     let endpoint = state
         .router
-        .clone()
+        .as_ref()
         .ok_or("Endpoint not initialized")?
         .endpoint()
         .clone();
-    let node_addr = state.get_node_addr(node_id).await.map_err(|err| err.to_string())?;
-    list_remote_files(&endpoint, node_addr, None)
+    let file_protocol = state
+        .file_protocol
+        .clone()
+        .ok_or("File protocol not initialized")?;
+    drop(state);
+
+    let hash = file_protocol
+        .ticket_collection(&node)
         .await
+        .map_err(|err| err.to_string())?;
+    let node_addr = endpoint.node_addr().await.map_err(|err| err.to_string())?;
+    BlobTicket::new(node_addr, hash, BlobFormat::HashSeq)
+        .map(|ticket| ticket.to_string())
         .map_err(|err| err.to_string())
 }
 
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn download_from_ticket(
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+    ticket: String,
+    destination: String,
+) -> Result<(), String> {
+    let ticket = BlobTicket::from_str(ticket.trim()).map_err(|_| "Invalid ticket".to_string())?;
+    let state = state.0.lock().await;
+    let blobs = state.blobs.clone().ok_or("Endpoint not initialized")?;
+    let bandwidth = state.bandwidth.clone().ok_or("Endpoint not initialized")?;
+    drop(state);
+
+    // The ticket carries the provider's addresses, so this works without discovery
+    let (node_addr, node) = ticket::ticket_target(&ticket);
+    let mut progress = ProgressReporter::new(app, node.name.clone());
+    let result = transfer::download(
+        &blobs,
+        &bandwidth,
+        node_addr,
+        &node,
+        &PathBuf::from(destination),
+        &mut progress,
+    )
+    .await;
+    match &result {
+        Ok(()) => progress.completed(),
+        Err(err) => progress.failed(err),
+    }
+    result.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn list_transfers(state: tauri::State<'_, AppStateWrapper>) -> Result<Vec<Transfer>, String> {
+    let state = state.0.lock().await;
+    let transfers = state
+        .transfers
+        .clone()
+        .ok_or("Transfer manager not initialized")?;
+    drop(state);
+    Ok(transfers.list().await)
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn pause_transfer(state: tauri::State<'_, AppStateWrapper>, id: u64) -> Result<(), String> {
+    let state = state.0.lock().await;
+    let transfers = state
+        .transfers
+        .clone()
+        .ok_or("Transfer manager not initialized")?;
+    drop(state);
+    transfers.pause(id).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn resume_transfer(state: tauri::State<'_, AppStateWrapper>, id: u64) -> Result<(), String> {
+    let state = state.0.lock().await;
+    let transfers = state
+        .transfers
+        .clone()
+        .ok_or("Transfer manager not initialized")?;
+    drop(state);
+    transfers.resume(id).await.map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn cancel_transfer(state: tauri::State<'_, AppStateWrapper>, id: u64) -> Result<(), String> {
+    let state = state.0.lock().await;
+    let transfers = state
+        .transfers
+        .clone()
+        .ok_or("Transfer manager not initialized")?;
+    drop(state);
+    transfers.cancel(id).await.map_err(|err| err.to_string())
+}
+
 #[instrument(skip_all, ret, err)]
 #[tauri::command]
 async fn clear_files(state: tauri::State<'_, AppStateWrapper>) -> Result<(), String> {
@@ -162,6 +761,85 @@ async fn get_peers(
     state.get_peers(app).await.map_err(|err| err.to_string())
 }
 
+/// Adds a peer that local discovery cannot see, from its node id and direct
+/// addresses or from a ticket it created, and keeps it as a favourite.
+#[instrument(skip(state, app), ret, err)]
+#[tauri::command]
+async fn add_peer(
+    node_id: Option<String>,
+    addresses: Vec<String>,
+    ticket: Option<String>,
+    name: Option<String>,
+    state: tauri::State<'_, AppStateWrapper>,
+    app: tauri::AppHandle,
+) -> Result<PeerSerializable, String> {
+    let node_addr = match ticket.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+        Some(ticket) => BlobTicket::from_str(ticket)
+            .map_err(|_| "Invalid ticket".to_string())?
+            .node_addr()
+            .clone(),
+        None => {
+            let node_id = node_id.ok_or("Node ID or ticket required")?;
+            let node_id = NodeId::from_str(node_id.trim()).map_err(|_| "Invalid node ID")?;
+            let addresses = addresses
+                .iter()
+                .filter(|addr| !addr.trim().is_empty())
+                .map(|addr| {
+                    SocketAddr::from_str(addr.trim()).map_err(|_| format!("Invalid address: {addr}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            iroh::NodeAddr::from_parts(node_id, None, addresses)
+        }
+    };
+    let name = name.map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+    let state = state.0.lock().await;
+    state
+        .add_favourite(&app, node_addr, name)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn remove_favourite(
+    node_id: String,
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<(), String> {
+    let node_id = NodeId::from_str(&node_id).map_err(|_| "Invalid node ID".to_string())?;
+    let state = state.0.lock().await;
+    state
+        .remove_favourite(node_id)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[derive(Debug, serde::Serialize)]
+struct NodeAddress {
+    node_id: NodeId,
+    addresses: Vec<SocketAddr>,
+}
+
+/// Our node id and direct addresses, for peers on other subnets to add us by hand.
+#[instrument(skip(state), ret, err)]
+#[tauri::command]
+async fn get_node_address(
+    state: tauri::State<'_, AppStateWrapper>,
+) -> Result<NodeAddress, String> {
+    let state = state.0.lock().await;
+    let endpoint = state
+        .router
+        .as_ref()
+        .ok_or("Endpoint not initialized")?
+        .endpoint()
+        .clone();
+    drop(state);
+    let node_addr = endpoint.node_addr().await.map_err(|err| err.to_string())?;
+    Ok(NodeAddress {
+        node_id: node_addr.node_id,
+        addresses: node_addr.direct_addresses.into_iter().collect(),
+    })
+}
+
 #[tauri::command]
 fn log(level: String, message: String, context: Option<serde_json::Value>) {
     match level.as_str() {
@@ -185,9 +863,21 @@ pub fn run() {
                 let window = app.get_webview_window("main").unwrap();
                 window.open_devtools();
             }
-            app.manage(AppStateWrapper(Arc::new(Mutex::new(
+            let state = Arc::new(Mutex::new(
                 AppState::new().expect("Failed to create AppState"),
-            ))));
+            ));
+            app.manage(AppStateWrapper(Arc::clone(&state)));
+
+            // Come back online under the username of the previous session
+            let app_handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let username = state.lock().await.get_username().clone();
+                if let Some(username) = username {
+                    if let Err(err) = go_online(&state, app_handle, username).await {
+                        error!("Failed to restore previous session: {err:#}");
+                    }
+                }
+            });
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -196,12 +886,56 @@ pub fn run() {
             add_path,
             clear_files,
             get_peers,
+            get_settings,
+            get_ban_status,
+            update_settings,
+            get_bandwidth_stats,
+            set_bandwidth_limits,
+            get_upload_slots,
+            get_slot_status,
+            add_peer,
+            remove_favourite,
+            get_node_address,
             log,
             ping_peer,
             get_uploaded_files_tree,
+            check_shares,
+            rescan_share,
+            list_shares,
+            set_share_watch,
+            set_share_visibility,
+            send_message,
+            get_conversation,
+            get_outbox,
+            get_message_status,
+            create_room,
+            join_room,
+            leave_room,
+            list_rooms,
+            get_room_ticket,
+            get_room_messages,
+            send_room_message,
             get_remote_files,
-            remove_files
+            get_remote_children,
+            search,
+            remove_files,
+            download,
+            swarm_download,
+            create_share_ticket,
+            download_from_ticket,
+            list_transfers,
+            pause_transfer,
+            resume_transfer,
+            cancel_transfer
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                let state = Arc::clone(&app.state::<AppStateWrapper>().0);
+                tauri::async_runtime::block_on(async move {
+                    state.lock().await.go_offline().await;
+                });
+            }
+        });
 }
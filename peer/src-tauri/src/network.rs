@@ -1,2 +1,17 @@
+pub mod bandwidth;
+pub mod chat;
 pub mod discovery;
+pub mod favourites;
+pub mod gate;
+pub mod host;
+pub mod moderation;
+pub mod outbox;
 pub mod protocol;
+pub mod queue;
+pub mod rooms;
+pub mod search;
+pub mod sessions;
+pub mod slots;
+pub mod swarm;
+pub mod ticket;
+pub mod transfer;
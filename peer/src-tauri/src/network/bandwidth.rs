@@ -0,0 +1,229 @@
+use iroh::NodeId;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::settings::{BandwidthSettings, SharedSettings};
+
+/// Throughput is averaged over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Blobs served to peers.
+    Upload,
+    /// Blobs fetched from peers.
+    Download,
+}
+
+/// Current throughput, as returned by `get_bandwidth_stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthStats {
+    pub upload_bytes_per_sec: u64,
+    pub download_bytes_per_sec: u64,
+    pub total_uploaded: u64,
+    pub total_downloaded: u64,
+    /// Peers that transferred data within the last few seconds.
+    pub peers: Vec<PeerBandwidth>,
+    pub limits: BandwidthSettings,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerBandwidth {
+    pub node_id: NodeId,
+    pub upload_bytes_per_sec: u64,
+    pub download_bytes_per_sec: u64,
+}
+
+/// Meters the bytes served and fetched, and paces them to the limits of the
+/// [`BandwidthSettings`].
+///
+/// Transfers report their bytes with [`Bandwidth::consume`] as they go, which waits
+/// long enough to keep them under both the global limit and the limit for the peer.
+/// The limits are read on every call, so changes apply to running transfers.
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    settings: SharedSettings,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    upload: Meter,
+    download: Meter,
+    peers: HashMap<NodeId, (Meter, Meter)>,
+}
+
+/// Throughput and pacing of one direction, globally or for one peer.
+#[derive(Debug, Default)]
+struct Meter {
+    total: u64,
+    recent: VecDeque<(Instant, u64)>,
+    /// When the bytes allowed so far will have been sent at the limit.
+    next_free: Option<Instant>,
+}
+
+impl Meter {
+    /// Records `bytes` that were just transferred, returns when transferring them would
+    /// have finished at `limit_kib`, after everything transferred before.
+    fn consume(&mut self, now: Instant, bytes: u64, limit_kib: Option<u64>) -> Instant {
+        self.total += bytes;
+        self.recent.push_back((now, bytes));
+        self.prune(now);
+        let Some(limit_kib) = limit_kib else {
+            self.next_free = None;
+            return now;
+        };
+        let start = self.next_free.map_or(now, |next_free| next_free.max(now));
+        let ready = start + Duration::from_secs_f64(bytes as f64 / (limit_kib as f64 * 1024.0));
+        self.next_free = Some(ready);
+        ready
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some((at, _)) = self.recent.front() {
+            if now.duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    fn rate(&mut self, now: Instant) -> u64 {
+        self.prune(now);
+        let bytes: u64 = self.recent.iter().map(|(_, bytes)| bytes).sum();
+        bytes / RATE_WINDOW.as_secs()
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.recent.is_empty() && self.next_free.is_none_or(|next_free| next_free <= now)
+    }
+}
+
+impl Bandwidth {
+    pub fn new(settings: SharedSettings) -> Self {
+        Self {
+            settings,
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    /// Records `bytes` just transferred with `node_id`, and waits until the limits allow
+    /// the transfer to go on.
+    pub async fn consume(&self, flow: Flow, node_id: NodeId, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let limits = self.settings.lock().await.bandwidth.clone();
+        let (global_limit, peer_limit) = match flow {
+            Flow::Upload => (limits.upload_limit_kib, limits.peer_upload_limit_kib),
+            Flow::Download => (limits.download_limit_kib, limits.peer_download_limit_kib),
+        };
+        let now = Instant::now();
+        let ready = {
+            let mut inner = self.inner.lock().await;
+            let global = match flow {
+                Flow::Upload => &mut inner.upload,
+                Flow::Download => &mut inner.download,
+            };
+            let global_ready = global.consume(now, bytes, global_limit);
+            let (upload, download) = inner.peers.entry(node_id).or_default();
+            let peer = match flow {
+                Flow::Upload => upload,
+                Flow::Download => download,
+            };
+            global_ready.max(peer.consume(now, bytes, peer_limit))
+        };
+        tokio::time::sleep_until(ready).await;
+    }
+
+    pub async fn stats(&self) -> BandwidthStats {
+        let limits = self.settings.lock().await.bandwidth.clone();
+        let now = Instant::now();
+        let mut inner = self.inner.lock().await;
+        inner
+            .peers
+            .retain(|_, (upload, download)| !upload.is_idle(now) || !download.is_idle(now));
+        let peers = inner
+            .peers
+            .iter_mut()
+            .map(|(node_id, (upload, download))| PeerBandwidth {
+                node_id: *node_id,
+                upload_bytes_per_sec: upload.rate(now),
+                download_bytes_per_sec: download.rate(now),
+            })
+            .collect();
+        BandwidthStats {
+            upload_bytes_per_sec: inner.upload.rate(now),
+            download_bytes_per_sec: inner.download.rate(now),
+            total_uploaded: inner.upload.total,
+            total_downloaded: inner.download.total,
+            peers,
+            limits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_meter_never_waits() {
+        let mut meter = Meter::default();
+        let now = Instant::now();
+        assert_eq!(meter.consume(now, 10 * 1024 * 1024, None), now);
+        assert_eq!(meter.consume(now, 10 * 1024 * 1024, None), now);
+        assert_eq!(meter.total, 20 * 1024 * 1024);
+        assert!(meter.is_idle(now + RATE_WINDOW * 2));
+    }
+
+    #[test]
+    fn limited_meter_queues_bytes_behind_each_other() {
+        let mut meter = Meter::default();
+        let now = Instant::now();
+        // 100 KiB at 100 KiB/s take a second each
+        assert_eq!(
+            meter.consume(now, 100 * 1024, Some(100)),
+            now + Duration::from_secs(1)
+        );
+        assert_eq!(
+            meter.consume(now, 100 * 1024, Some(100)),
+            now + Duration::from_secs(2)
+        );
+        assert!(!meter.is_idle(now + Duration::from_secs(1)));
+
+        // Time that passed without transfers is not saved up
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            meter.consume(later, 50 * 1024, Some(100)),
+            later + Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn huge_limits_do_not_overflow() {
+        let mut meter = Meter::default();
+        let now = Instant::now();
+        assert!(meter.consume(now, 1024, Some(u64::MAX)) < now + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn rate_averages_over_the_window() {
+        let mut meter = Meter::default();
+        let now = Instant::now();
+        meter.consume(now, 5000, None);
+        meter.consume(now + Duration::from_secs(1), 5000, None);
+        assert_eq!(
+            meter.rate(now + Duration::from_secs(1)),
+            10000 / RATE_WINDOW.as_secs()
+        );
+        assert_eq!(
+            meter.rate(now + RATE_WINDOW + Duration::from_millis(500)),
+            5000 / RATE_WINDOW.as_secs()
+        );
+        assert_eq!(meter.rate(now + RATE_WINDOW * 3), 0);
+    }
+}
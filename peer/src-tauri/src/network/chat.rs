@@ -0,0 +1,317 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+const CHAT_DIR: &str = "chat";
+/// Longest text accepted in a single message, in bytes.
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024;
+/// Queued messages not delivered within this time are given up on.
+const MESSAGE_TTL: chrono::TimeDelta = chrono::TimeDelta::days(7);
+/// Messages kept per conversation, older ones are dropped as new ones arrive.
+const MAX_CONVERSATION_LEN: usize = 1000;
+
+/// A text message as sent over the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Chosen by the sender, acknowledged by the receiver.
+    pub id: u64,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl ChatMessage {
+    pub fn new(text: String) -> Self {
+        Self {
+            // 53 bits, so the id survives the round trip through a JavaScript number
+            id: rand::random::<u64>() >> 11,
+            text,
+            sent_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting in the outbox until the peer is online.
+    Queued,
+    /// Acknowledged by the peer, incoming messages are always delivered.
+    Delivered,
+    /// The peer did not come online within [`MESSAGE_TTL`].
+    Expired,
+}
+
+/// A message in the local history of a conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub message: ChatMessage,
+    pub direction: Direction,
+    pub status: DeliveryStatus,
+}
+
+/// Payload of `chat::message` and `chat::status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatEvent {
+    pub node_id: NodeId,
+    pub message: StoredMessage,
+}
+
+/// Conversations with other peers, one JSON file per `NodeId` under `APP_DATA_DIR`.
+#[derive(Debug, Clone)]
+pub struct ChatHistory {
+    conversations: Arc<Mutex<HashMap<NodeId, Vec<StoredMessage>>>>,
+    app_handle: AppHandle,
+}
+
+impl ChatHistory {
+    /// Loads all conversations, so messages still queued from a previous session are
+    /// known before their peers come online.
+    pub fn new(app: AppHandle) -> Self {
+        let conversations = std::fs::read_dir(crate::global::APP_DATA_DIR.join(CHAT_DIR))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let node_id: NodeId = path.file_stem()?.to_str()?.parse().ok()?;
+                Some((node_id, load_conversation(node_id)))
+            })
+            .collect();
+        Self {
+            conversations: Arc::new(Mutex::new(conversations)),
+            app_handle: app,
+        }
+    }
+
+    /// All messages exchanged with `node_id`, oldest first.
+    pub async fn conversation(&self, node_id: NodeId) -> Vec<StoredMessage> {
+        let mut conversations = self.conversations.lock().await;
+        conversation_mut(&mut conversations, node_id).clone()
+    }
+
+    /// Outgoing messages waiting for the peer to come online, across all conversations.
+    pub async fn outbox(&self) -> Vec<ChatEvent> {
+        let conversations = self.conversations.lock().await;
+        conversations
+            .iter()
+            .flat_map(|(node_id, conversation)| {
+                conversation
+                    .iter()
+                    .filter(|m| m.status == DeliveryStatus::Queued)
+                    .map(|m| ChatEvent {
+                        node_id: *node_id,
+                        message: m.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Queues a message for delivery to `node_id`.
+    pub async fn record_outgoing(
+        &self,
+        node_id: NodeId,
+        message: ChatMessage,
+    ) -> Result<StoredMessage> {
+        let stored = StoredMessage {
+            message,
+            direction: Direction::Outgoing,
+            status: DeliveryStatus::Queued,
+        };
+        let mut conversations = self.conversations.lock().await;
+        let conversation = conversation_mut(&mut conversations, node_id);
+        conversation.push(stored.clone());
+        drop_oldest(conversation);
+        save_conversation(node_id, conversation).await?;
+        Ok(stored)
+    }
+
+    /// Stores a message received from `node_id` and emits `chat::message`. A message
+    /// that was already received, because the peer sent it again, is ignored.
+    pub async fn record_incoming(&self, node_id: NodeId, message: ChatMessage) -> Result<()> {
+        let mut conversations = self.conversations.lock().await;
+        let conversation = conversation_mut(&mut conversations, node_id);
+        if conversation
+            .iter()
+            .any(|m| m.direction == Direction::Incoming && m.message.id == message.id)
+        {
+            return Ok(());
+        }
+        let stored = StoredMessage {
+            message,
+            direction: Direction::Incoming,
+            status: DeliveryStatus::Delivered,
+        };
+        conversation.push(stored.clone());
+        drop_oldest(conversation);
+        save_conversation(node_id, conversation).await?;
+        let payload = ChatEvent {
+            node_id,
+            message: stored,
+        };
+        let _ = self.app_handle.emit("chat::message", payload);
+        Ok(())
+    }
+
+    /// The queued messages for `node_id`, oldest first. Messages queued for longer
+    /// than [`MESSAGE_TTL`] are marked as expired instead.
+    pub async fn queued(&self, node_id: NodeId) -> Result<Vec<ChatMessage>> {
+        let mut conversations = self.conversations.lock().await;
+        let conversation = conversation_mut(&mut conversations, node_id);
+        let expire_before = Utc::now() - MESSAGE_TTL;
+        let mut queued = Vec::new();
+        let mut expired = Vec::new();
+        for stored in conversation
+            .iter_mut()
+            .filter(|m| m.status == DeliveryStatus::Queued)
+        {
+            if stored.message.sent_at < expire_before {
+                stored.status = DeliveryStatus::Expired;
+                expired.push(stored.clone());
+            } else {
+                queued.push(stored.message.clone());
+            }
+        }
+        if !expired.is_empty() {
+            save_conversation(node_id, conversation).await?;
+            for message in expired {
+                self.emit_status(node_id, message);
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Marks an outgoing message as acknowledged by the peer and emits `chat::status`.
+    pub async fn mark_delivered(&self, node_id: NodeId, id: u64) -> Result<StoredMessage> {
+        let mut conversations = self.conversations.lock().await;
+        let conversation = conversation_mut(&mut conversations, node_id);
+        let stored = conversation
+            .iter_mut()
+            .find(|m| m.direction == Direction::Outgoing && m.message.id == id)
+            .context("Unknown message")?;
+        stored.status = DeliveryStatus::Delivered;
+        let stored = stored.clone();
+        save_conversation(node_id, conversation).await?;
+        self.emit_status(node_id, stored.clone());
+        Ok(stored)
+    }
+
+    /// Looks up a single message of the conversation with `node_id`.
+    pub async fn message(
+        &self,
+        node_id: NodeId,
+        id: u64,
+        direction: Direction,
+    ) -> Option<StoredMessage> {
+        let mut conversations = self.conversations.lock().await;
+        conversation_mut(&mut conversations, node_id)
+            .iter()
+            .find(|m| m.direction == direction && m.message.id == id)
+            .cloned()
+    }
+
+    fn emit_status(&self, node_id: NodeId, message: StoredMessage) {
+        let payload = ChatEvent { node_id, message };
+        let _ = self.app_handle.emit("chat::status", payload);
+    }
+}
+
+/// The conversation with `node_id`, loaded from disk on first access.
+fn conversation_mut(
+    conversations: &mut HashMap<NodeId, Vec<StoredMessage>>,
+    node_id: NodeId,
+) -> &mut Vec<StoredMessage> {
+    conversations
+        .entry(node_id)
+        .or_insert_with(|| load_conversation(node_id))
+}
+
+/// Drops the oldest messages beyond [`MAX_CONVERSATION_LEN`]. Messages still queued
+/// are kept, they are dropped once delivered or expired.
+fn drop_oldest(conversation: &mut Vec<StoredMessage>) {
+    let mut excess = conversation.len().saturating_sub(MAX_CONVERSATION_LEN);
+    conversation.retain(|m| {
+        if excess > 0 && m.status != DeliveryStatus::Queued {
+            excess -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+fn conversation_path(node_id: NodeId) -> PathBuf {
+    crate::global::APP_DATA_DIR
+        .join(CHAT_DIR)
+        .join(format!("{node_id}.json"))
+}
+
+fn load_conversation(node_id: NodeId) -> Vec<StoredMessage> {
+    let Ok(data) = std::fs::read(conversation_path(node_id)) else {
+        return Vec::new();
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|err| {
+        warn!("Discarding unreadable chat history with {node_id}: {err}");
+        Vec::new()
+    })
+}
+
+async fn save_conversation(node_id: NodeId, conversation: &[StoredMessage]) -> Result<()> {
+    let path = conversation_path(node_id);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let data = serde_json::to_vec(conversation)?;
+    tokio::fs::write(&path, data)
+        .await
+        .context("Failed to save chat history")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(status: DeliveryStatus) -> StoredMessage {
+        StoredMessage {
+            message: ChatMessage::new("hi".to_owned()),
+            direction: Direction::Outgoing,
+            status,
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_messages() {
+        let mut conversation: Vec<_> = (0..MAX_CONVERSATION_LEN + 2)
+            .map(|_| stored(DeliveryStatus::Delivered))
+            .collect();
+        let newest: Vec<u64> = conversation[2..].iter().map(|m| m.message.id).collect();
+        drop_oldest(&mut conversation);
+        let kept: Vec<u64> = conversation.iter().map(|m| m.message.id).collect();
+        assert_eq!(kept, newest);
+    }
+
+    #[test]
+    fn keeps_queued_messages() {
+        let mut conversation = vec![stored(DeliveryStatus::Queued)];
+        conversation.extend((0..MAX_CONVERSATION_LEN).map(|_| stored(DeliveryStatus::Delivered)));
+        let queued = conversation[0].message.id;
+        drop_oldest(&mut conversation);
+        assert_eq!(conversation.len(), MAX_CONVERSATION_LEN);
+        assert_eq!(conversation[0].message.id, queued);
+        assert_eq!(conversation[0].status, DeliveryStatus::Queued);
+    }
+}
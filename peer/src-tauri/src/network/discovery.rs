@@ -1,17 +1,36 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Result;
+use futures_buffered::join_all;
 use futures_lite::StreamExt;
 use tauri::Emitter;
-use tauri::{AppHandle, Manager};
-use tokio::sync::Mutex;
+use tauri::{AppHandle, Listener, Manager};
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::Instant;
 
+use crate::network::host::{self, Verification, HOST_ADDR};
+use crate::network::protocol::client;
+use crate::network::sessions::Sessions;
+use crate::settings::SharedSettings;
 use crate::state::AppStateWrapper;
 use crate::state::Peer;
 use crate::state::PeerSerializable;
-use tracing::{error, info, instrument};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use serde::Serialize;
+use tracing::{debug, error, info, instrument, warn};
+
+/// How long a favourite has to answer the ping that keeps it from being marked as away.
+const FAVOURITE_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Payload of `peer::impersonation_detected`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImpersonationDetected {
+    pub peer: PeerSerializable,
+    /// Nickname the host has registered for the peer's node id, if any.
+    pub registered_username: Option<String>,
+}
 
 #[instrument(skip_all, ret, err)]
 pub async fn run_discovery(app: AppHandle) -> Result<()> {
@@ -22,12 +41,17 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
     let mut state = state_lock.0.lock().await;
 
     let mut stream = state.router.as_mut().unwrap().endpoint().discovery_stream();
+    let endpoint = state.router.as_ref().unwrap().endpoint().clone();
     let peers = Arc::clone(&state.peers);
+    let settings = Arc::clone(&state.settings);
+    let moderation = state.moderation.clone();
+    let sessions = state.sessions.clone();
     drop(state);
     let cleaner_handle = tokio::spawn(background_cleanup_task(
         Arc::clone(&peers),
+        sessions,
         app.clone(),
-        tokio::time::Duration::from_secs(10), // TODO make this configurable
+        settings,
     ));
 
     while let Some(event) = stream.next().await {
@@ -42,14 +66,24 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
                     .map(|ud| ud.as_ref())
                     .unwrap_or("");
 
-                // TODO verify username is with the host
-                //if !host.contains(&user_data) {
-                //    continue;
-                //}
+                // Banned nodes are hidden, as if discovery never found them
+                if let Some(moderation) = &moderation {
+                    if moderation
+                        .ban_for(node_addr.node_id, Some(user_data))
+                        .await
+                        .is_some()
+                    {
+                        continue;
+                    }
+                }
+
                 let peer = Peer {
                     node_addr,
                     username: user_data.to_owned(),
                     last_seen: Instant::now(),
+                    verified: false,
+                    favourite: false,
+                    away: false,
                 };
 
                 {
@@ -64,7 +98,11 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
                             "Peer username changed: {} -> {}",
                             old_peer.username, peer.username
                         );
-                        *old_peer = peer;
+                        *old_peer = Peer {
+                            favourite: old_peer.favourite,
+                            ..peer.clone()
+                        };
+                        spawn_verification(endpoint.clone(), Arc::clone(&peers), app.clone(), peer);
                     } else if !peer_lock
                         .iter()
                         .any(|p| p.node_addr.node_id == peer.node_addr.node_id)
@@ -73,6 +111,7 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
                         let payload: PeerSerializable = peer.clone().into();
                         let _ = app.emit("peer::added", payload);
                         info!("New peer added: {}", peer.username);
+                        spawn_verification(endpoint.clone(), Arc::clone(&peers), app.clone(), peer);
                     } else {
                         // Update last seen time for existing peer
                         if let Some(existing_peer) = peer_lock
@@ -80,6 +119,12 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
                             .find(|p| p.node_addr.node_id == peer.node_addr.node_id)
                         {
                             existing_peer.last_seen = Instant::now();
+                            if existing_peer.away {
+                                existing_peer.away = false;
+                                let payload: PeerSerializable = existing_peer.clone().into();
+                                let _ = app.emit("peer::back", payload);
+                                info!("Peer is back: {}", existing_peer.username);
+                            }
                         }
                     }
                 }
@@ -94,22 +139,109 @@ pub async fn run_discovery(app: AppHandle) -> Result<()> {
     Ok(())
 }
 
-/// Periodically checks for peers that have not been seen within the timeout period,
-/// removes them from the tracker, and emits a "peer::left" event for each.
-#[instrument(skip(peers, app))]
+/// Checks the username `peer` advertises against the host in the background, marking
+/// the peer as verified or, if the username is registered to another node, emitting
+/// `peer::impersonation_detected`.
+fn spawn_verification(
+    endpoint: Endpoint,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    app: AppHandle,
+    peer: Peer,
+) {
+    if HOST_ADDR.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let node_id = peer.node_addr.node_id;
+        let verification = match host::verify(&endpoint, node_id, &peer.username).await {
+            Ok(verification) => verification,
+            Err(err) => {
+                warn!("Could not verify {} with the host: {err:#}", peer.username);
+                return;
+            }
+        };
+
+        let mut peer_lock = peers.lock().await;
+        // The peer may have left or changed its username in the meantime
+        let Some(current) = peer_lock
+            .iter_mut()
+            .find(|p| p.node_addr.node_id == node_id && p.username == peer.username)
+        else {
+            return;
+        };
+        match verification {
+            Verification::Verified => {
+                current.verified = true;
+                let payload: PeerSerializable = current.clone().into();
+                let _ = app.emit("peer::verified", payload);
+            }
+            Verification::Unregistered => {
+                current.verified = false;
+                debug!(
+                    "Username {} of {node_id} is not registered",
+                    current.username
+                );
+            }
+            Verification::Impersonation { registered } => {
+                current.verified = false;
+                warn!(
+                    "Peer {node_id} claims to be {}, which is registered to another node",
+                    current.username
+                );
+                let payload = ImpersonationDetected {
+                    peer: current.clone().into(),
+                    registered_username: registered,
+                };
+                let _ = app.emit("peer::impersonation_detected", payload);
+            }
+        }
+    });
+}
+
+/// Periodically checks for peers that have not been seen within the discovery timeout.
+/// They are first marked as away with a "peer::away" event, and once the grace period
+/// has passed as well they are removed with a "peer::left" event. Favourites are never
+/// removed, and are pinged before they are marked as away, see [`refresh_favourites`].
+/// A "settings::changed" event restarts the wait with the new settings.
+#[instrument(skip_all)]
 pub async fn background_cleanup_task(
     peers: Arc<Mutex<Vec<Peer>>>,
+    sessions: Option<Sessions>,
     app: AppHandle,
-    timeout: tokio::time::Duration,
+    settings: SharedSettings,
 ) {
-    let mut interval = tokio::time::interval(timeout);
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    let listener = app.listen("settings::changed", move |_| {
+        let _ = changed_tx.send(());
+    });
+    // Stop listening when the task is aborted together with discovery
+    let _unlisten = ListenerGuard(app.clone(), listener);
     loop {
-        interval.tick().await;
+        let discovery = settings.lock().await.discovery.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(discovery.sweep_interval()) => {}
+            Some(()) = changed_rx.recv() => continue,
+        }
+        let timeout = discovery.timeout();
+        let left_after = timeout + discovery.away_grace();
+        if let Some(sessions) = &sessions {
+            refresh_favourites(&peers, sessions, timeout, &app).await;
+        }
+
         let mut peers_lock = peers.lock().await;
+        let now = Instant::now();
+        for peer in peers_lock.iter_mut() {
+            if !peer.away && now.duration_since(peer.last_seen) > timeout {
+                peer.away = true;
+                let payload: PeerSerializable = peer.clone().into();
+                let _ = app.emit("peer::away", payload);
+                info!("Peer is away: {}", peer.username);
+            }
+        }
 
         let left_peers: Vec<Peer> = peers_lock
             .iter()
-            .filter(|peer| Instant::now().duration_since(peer.last_seen) > timeout)
+            .filter(|peer| !peer.favourite && now.duration_since(peer.last_seen) > left_after)
             .cloned()
             .collect();
 
@@ -121,3 +253,64 @@ pub async fn background_cleanup_task(
         }
     }
 }
+
+/// Pings the favourites local discovery has not seen within `timeout`, and marks those
+/// that answer as seen. Favourites added by address, on another subnet for example,
+/// are never announced by local discovery and would be marked as away otherwise.
+async fn refresh_favourites(
+    peers: &Mutex<Vec<Peer>>,
+    sessions: &Sessions,
+    timeout: Duration,
+    app: &AppHandle,
+) {
+    let now = Instant::now();
+    let stale: Vec<NodeAddr> = peers
+        .lock()
+        .await
+        .iter()
+        .filter(|peer| peer.favourite && now.duration_since(peer.last_seen) > timeout)
+        .map(|peer| peer.node_addr.clone())
+        .collect();
+    if stale.is_empty() {
+        return;
+    }
+
+    let pings = stale.into_iter().map(|node_addr| async move {
+        let node_id = node_addr.node_id;
+        let ping = client::ping_peer(sessions, node_addr);
+        match tokio::time::timeout(FAVOURITE_PING_TIMEOUT, ping).await {
+            Ok(Ok(())) => Some(node_id),
+            Ok(Err(err)) => {
+                debug!("Favourite {node_id} did not answer: {err:#}");
+                None
+            }
+            Err(_) => {
+                debug!("Favourite {node_id} did not answer in time");
+                None
+            }
+        }
+    });
+    let reachable: Vec<NodeId> = join_all(pings).await.iter().flatten().copied().collect();
+
+    let mut peers = peers.lock().await;
+    for peer in peers
+        .iter_mut()
+        .filter(|peer| reachable.contains(&peer.node_addr.node_id))
+    {
+        peer.last_seen = Instant::now();
+        if peer.away {
+            peer.away = false;
+            let payload: PeerSerializable = peer.clone().into();
+            let _ = app.emit("peer::back", payload);
+            info!("Peer is back: {}", peer.username);
+        }
+    }
+}
+
+struct ListenerGuard(AppHandle, tauri::EventId);
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        self.0.unlisten(self.1);
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use iroh::endpoint::Endpoint;
+use iroh::{NodeAddr, NodeId};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::state::Peer;
+
+const FAVOURITES_FILE: &str = "favourites.json";
+
+/// A peer added by hand, kept across restarts and never evicted by discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Favourite {
+    pub node_id: NodeId,
+    pub addresses: Vec<SocketAddr>,
+    /// Shown until discovery reports the username the peer advertises.
+    pub name: String,
+}
+
+impl Favourite {
+    pub fn node_addr(&self) -> NodeAddr {
+        NodeAddr::from_parts(self.node_id, None, self.addresses.iter().copied())
+    }
+}
+
+/// Our favourites, loaded once and kept in memory so they can be checked on every
+/// request without reading the file.
+#[derive(Debug, Clone)]
+pub struct Favourites {
+    list: Arc<std::sync::RwLock<Vec<Favourite>>>,
+}
+
+impl Favourites {
+    pub fn load() -> Self {
+        Self {
+            list: Arc::new(std::sync::RwLock::new(load())),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Favourite> {
+        self.list.read().expect("poisoned").clone()
+    }
+
+    pub fn contains(&self, node_id: NodeId) -> bool {
+        self.list
+            .read()
+            .expect("poisoned")
+            .iter()
+            .any(|favourite| favourite.node_id == node_id)
+    }
+
+    /// Adds `favourite`, replacing an earlier entry for the same node.
+    pub async fn add(&self, favourite: Favourite) -> Result<()> {
+        let favourites = {
+            let mut list = self.list.write().expect("poisoned");
+            list.retain(|f| f.node_id != favourite.node_id);
+            list.push(favourite);
+            list.clone()
+        };
+        save(&favourites).await
+    }
+
+    pub async fn remove(&self, node_id: NodeId) -> Result<()> {
+        let favourites = {
+            let mut list = self.list.write().expect("poisoned");
+            list.retain(|f| f.node_id != node_id);
+            list.clone()
+        };
+        save(&favourites).await
+    }
+}
+
+fn load() -> Vec<Favourite> {
+    let path = crate::global::APP_DATA_DIR.join(FAVOURITES_FILE);
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+            warn!("Discarding unreadable favourites: {err}");
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+async fn save(favourites: &[Favourite]) -> Result<()> {
+    let path = crate::global::APP_DATA_DIR.join(FAVOURITES_FILE);
+    let data = serde_json::to_vec_pretty(favourites)?;
+    tokio::fs::write(&path, data)
+        .await
+        .context("Failed to save favourites")?;
+    Ok(())
+}
+
+/// Makes `favourite` reachable through `endpoint` and adds it to `peers`, or marks the
+/// peer as a favourite if discovery already found it. Returns the peer if it is new.
+pub async fn insert(
+    endpoint: &Endpoint,
+    peers: &Arc<Mutex<Vec<Peer>>>,
+    favourite: &Favourite,
+) -> Result<Option<Peer>> {
+    // Lets connections use the given addresses where local discovery cannot see the peer
+    if !favourite.addresses.is_empty() {
+        endpoint.add_node_addr(favourite.node_addr())?;
+    }
+    let mut peers = peers.lock().await;
+    if let Some(peer) = peers
+        .iter_mut()
+        .find(|peer| peer.node_addr.node_id == favourite.node_id)
+    {
+        peer.favourite = true;
+        return Ok(None);
+    }
+    let peer = Peer {
+        username: favourite.name.clone(),
+        node_addr: favourite.node_addr(),
+        last_seen: Instant::now(),
+        verified: false,
+        favourite: true,
+        away: false,
+    };
+    peers.push(peer.clone());
+    Ok(Some(peer))
+}
@@ -0,0 +1,238 @@
+use anyhow::Result;
+use bao_tree::io::fsm::encode_ranges_validated;
+use bao_tree::ChunkRanges;
+use bytes::Bytes;
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::protocol::ProtocolHandler;
+use iroh::NodeId;
+use iroh_blobs::hashseq::HashSeq;
+use iroh_blobs::net_protocol::Blobs;
+use iroh_blobs::protocol::Request;
+use iroh_blobs::store::{Map, MapEntry};
+use iroh_blobs::Hash;
+use iroh_io::AsyncStreamWriter;
+use std::sync::{Arc, OnceLock};
+use tracing::{debug, info};
+
+use crate::network::bandwidth::{Bandwidth, Flow};
+use crate::network::favourites::Favourites;
+use crate::network::protocol::FileProtocol;
+use crate::network::rooms::RoomManager;
+use crate::network::slots::UploadSlots;
+
+/// Application error code blob connections are closed with when they request a blob of
+/// a share that is not visible to the peer.
+pub const FORBIDDEN_ERROR_CODE: u32 = 403;
+
+/// Largest get request we read, a request is a hash and a few ranges.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Decides which blobs peers may fetch and holds the limits uploads are subject to.
+///
+/// Besides our shares and their tickets, see [`FileProtocol::may_fetch`], only the
+/// content of our chat rooms is served. Blobs are refused until the file protocol and
+/// the rooms are set.
+#[derive(Debug, Clone)]
+pub struct BlobsGate {
+    /// Set once the file protocol, which owns the index of our shares, is created.
+    protocol: Arc<OnceLock<FileProtocol>>,
+    /// Set once the chat rooms are opened.
+    rooms: Arc<OnceLock<RoomManager>>,
+    bandwidth: Bandwidth,
+    slots: UploadSlots,
+    favourites: Favourites,
+}
+
+impl BlobsGate {
+    pub fn new(bandwidth: Bandwidth, slots: UploadSlots, favourites: Favourites) -> Self {
+        Self {
+            protocol: Default::default(),
+            rooms: Default::default(),
+            bandwidth,
+            slots,
+            favourites,
+        }
+    }
+
+    pub fn set_protocol(&self, protocol: FileProtocol) {
+        let _ = self.protocol.set(protocol);
+    }
+
+    pub fn set_rooms(&self, rooms: RoomManager) {
+        let _ = self.rooms.set(rooms);
+    }
+
+    async fn may_fetch(&self, node_id: NodeId, hash: &Hash) -> bool {
+        let (Some(protocol), Some(rooms)) = (self.protocol.get(), self.rooms.get()) else {
+            info!("Refusing blob {hash}, not ready to serve blobs yet");
+            return false;
+        };
+        match protocol.may_fetch(node_id, hash).await {
+            Some(allowed) => allowed,
+            None => rooms.holds(hash).await,
+        }
+    }
+}
+
+/// Serves `iroh_blobs::ALPN` in place of the blobs provider, which has no way to
+/// refuse or slow down a request.
+///
+/// Every blob of a request is checked with the [`BlobsGate`] before it is sent, and
+/// the connection is closed when the peer may not fetch one. Allowed requests wait
+/// until the peer gets an upload slot, which they keep until they are served, see
+/// [`UploadSlots`]. Every write to the stream first waits for the [`Bandwidth`]
+/// limits, so uploads are paced as they are sent.
+#[derive(Debug, Clone)]
+pub struct GatedBlobs {
+    blobs: Blobs<iroh_blobs::store::fs::Store>,
+    gate: BlobsGate,
+}
+
+impl GatedBlobs {
+    pub fn new(blobs: Blobs<iroh_blobs::store::fs::Store>, gate: BlobsGate) -> Self {
+        Self { blobs, gate }
+    }
+
+    /// Serves the requests of one connection, each on its own stream.
+    async fn serve(self, connection: Connection) -> Result<()> {
+        let node_id = connection.remote_node_id()?;
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            let this = self.clone();
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                if let Err(err) = this.serve_request(&connection, node_id, send, recv).await {
+                    debug!("Blob request from {node_id} failed: {err:#}");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn serve_request(
+        &self,
+        connection: &Connection,
+        node_id: NodeId,
+        send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        let request = recv.read_to_end(MAX_REQUEST_SIZE).await?;
+        let request = match postcard::from_bytes(&request)? {
+            Request::Get(request) => request,
+            #[allow(unreachable_patterns)]
+            _ => anyhow::bail!("Unsupported blob request"),
+        };
+        if !self.gate.may_fetch(node_id, &request.hash).await {
+            refuse(connection, node_id, &request.hash);
+            return Ok(());
+        }
+        let favourite = self.gate.favourites.contains(node_id);
+        let _slot = tokio::select! {
+            slot = self.gate.slots.acquire(node_id, favourite) => slot,
+            // Gave up waiting in line
+            _ = connection.closed() => return Ok(()),
+        };
+
+        let mut writer = PacedWriter {
+            send,
+            bandwidth: self.gate.bandwidth.clone(),
+            node_id,
+        };
+        let mut children = None;
+        for (offset, ranges) in request.ranges.iter_non_empty() {
+            let hash = if offset == 0 {
+                request.hash
+            } else {
+                // The other offsets are the blobs of the hash sequence
+                if children.is_none() {
+                    let root = self.blobs.client().read_to_bytes(request.hash).await?;
+                    children = Some(HashSeq::try_from(root)?);
+                }
+                let child = children
+                    .as_ref()
+                    .and_then(|children| children.iter().nth(offset as usize - 1));
+                let Some(hash) = child else {
+                    break;
+                };
+                if !self.gate.may_fetch(node_id, &hash).await {
+                    refuse(connection, node_id, &hash);
+                    return Ok(());
+                }
+                hash
+            };
+            if !self
+                .send_blob(hash, &ranges.to_chunk_ranges(), &mut writer)
+                .await?
+            {
+                break;
+            }
+        }
+        writer.send.finish()?;
+        Ok(())
+    }
+
+    /// Sends `ranges` of the blob `hash`, returns whether we have the blob.
+    async fn send_blob(
+        &self,
+        hash: Hash,
+        ranges: &ChunkRanges,
+        writer: &mut PacedWriter,
+    ) -> Result<bool> {
+        let Some(entry) = self.blobs.store().get(&hash).await? else {
+            return Ok(false);
+        };
+        let outboard = entry.outboard().await?;
+        let data = entry.data_reader().await?;
+        encode_ranges_validated(data, outboard, ranges, writer).await?;
+        Ok(true)
+    }
+}
+
+fn refuse(connection: &Connection, node_id: NodeId, hash: &Hash) {
+    info!("Refusing blob {hash} to {node_id}, it is not shared with them");
+    connection.close(FORBIDDEN_ERROR_CODE.into(), b"Not shared with you");
+}
+
+/// The send stream of a request, every write waits for the upload limits first.
+struct PacedWriter {
+    send: SendStream,
+    bandwidth: Bandwidth,
+    node_id: NodeId,
+}
+
+impl AsyncStreamWriter for PacedWriter {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.bandwidth
+            .consume(Flow::Upload, self.node_id, data.len() as u64)
+            .await;
+        self.send
+            .write_all(data)
+            .await
+            .map_err(std::io::Error::from)
+    }
+
+    async fn write_bytes(&mut self, data: Bytes) -> std::io::Result<()> {
+        self.bandwidth
+            .consume(Flow::Upload, self.node_id, data.len() as u64)
+            .await;
+        self.send
+            .write_chunk(data)
+            .await
+            .map_err(std::io::Error::from)
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for GatedBlobs {
+    fn accept(&self, connection: Connection) -> BoxedFuture<Result<()>> {
+        Box::pin(self.clone().serve(connection))
+    }
+
+    fn shutdown(&self) -> BoxedFuture<()> {
+        let blobs = self.blobs.clone();
+        Box::pin(async move { blobs.shutdown().await })
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use iroh::endpoint::Endpoint;
+use iroh::{NodeAddr, NodeId};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tracing::{info, instrument, warn};
+
+use crate::network::protocol::{recv_msg, send_msg};
+
+/// Keep in sync with `host/src/protocol.rs`.
+pub const ALPN: &[u8] = b"hermes/host/0";
+
+/// Address of the host, from `HERMES_HOST` (its node id) and optionally
+/// `HERMES_HOST_ADDRS` (comma separated socket addresses). `None` if no host is set.
+pub static HOST_ADDR: LazyLock<Option<NodeAddr>> = LazyLock::new(|| {
+    let node_id = std::env::var("HERMES_HOST").ok()?;
+    let node_id = match NodeId::from_str(node_id.trim()) {
+        Ok(node_id) => node_id,
+        Err(err) => {
+            warn!("Ignoring invalid HERMES_HOST: {err}");
+            return None;
+        }
+    };
+    let direct_addresses: Vec<SocketAddr> = std::env::var("HERMES_HOST_ADDRS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|addr| addr.trim().parse().ok())
+        .collect();
+    Some(NodeAddr::from_parts(node_id, None, direct_addresses))
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum HostRequest {
+    Register { nickname: String },
+    LookupNode { node_id: NodeId },
+    LookupNickname { nickname: String },
+    Quit,
+    ListBans,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum HostResponse {
+    Registered,
+    Rejected {
+        reason: String,
+    },
+    Registration {
+        node_id: Option<NodeId>,
+        nickname: Option<String>,
+    },
+    Bans {
+        bans: Vec<BanEntry>,
+    },
+}
+
+/// A ban set by the host operator. Nickname bans carry the node id registered for the
+/// nickname, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub node_id: Option<NodeId>,
+    pub nickname: Option<String>,
+    /// Shown to the banned user.
+    pub reason: String,
+    /// Seconds since the unix epoch, `None` for a permanent ban.
+    pub expires_at: Option<u64>,
+}
+
+/// Result of checking a nickname a peer advertises against the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The host has this nickname registered for the node.
+    Verified,
+    /// Nobody registered this nickname, so the node may use it but is not verified.
+    Unregistered,
+    /// The host has this nickname registered for a different node. `registered` is the
+    /// nickname registered for the node itself, if any.
+    Impersonation { registered: Option<String> },
+}
+
+async fn request(endpoint: &Endpoint, request: HostRequest) -> Result<HostResponse> {
+    let host = HOST_ADDR.clone().context("No host configured")?;
+    let conn = endpoint.connect(host, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_msg(&mut send, &request).await?;
+    let response: HostResponse = recv_msg(&mut recv).await?;
+    send_msg(&mut send, &HostRequest::Quit).await?;
+    send.finish()?;
+    Ok(response)
+}
+
+/// Registers `nickname` for our node id with the host.
+#[instrument(skip(endpoint), err)]
+pub async fn register(endpoint: &Endpoint, nickname: &str) -> Result<()> {
+    let response = request(
+        endpoint,
+        HostRequest::Register {
+            nickname: nickname.to_owned(),
+        },
+    )
+    .await?;
+    match response {
+        HostResponse::Registered => {
+            info!("Registered nickname {nickname} with the host");
+            Ok(())
+        }
+        HostResponse::Rejected { reason } => Err(anyhow::anyhow!(reason)),
+        _ => Err(anyhow::anyhow!("Unexpected response type")),
+    }
+}
+
+/// Checks whether `nickname` is registered, and whether to `node_id`.
+#[instrument(skip(endpoint), ret, err)]
+pub async fn verify(endpoint: &Endpoint, node_id: NodeId, nickname: &str) -> Result<Verification> {
+    let lookup = HostRequest::LookupNickname {
+        nickname: nickname.to_owned(),
+    };
+    let owner = match request(endpoint, lookup).await? {
+        HostResponse::Registration { node_id, .. } => node_id,
+        _ => return Err(anyhow::anyhow!("Unexpected response type")),
+    };
+    match owner {
+        Some(owner) if owner == node_id => Ok(Verification::Verified),
+        None => Ok(Verification::Unregistered),
+        Some(_) => match request(endpoint, HostRequest::LookupNode { node_id }).await? {
+            HostResponse::Registration { nickname, .. } => Ok(Verification::Impersonation {
+                registered: nickname,
+            }),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        },
+    }
+}
+
+/// The bans currently in force on the host.
+#[instrument(skip(endpoint), err)]
+pub async fn fetch_bans(endpoint: &Endpoint) -> Result<Vec<BanEntry>> {
+    match request(endpoint, HostRequest::ListBans).await? {
+        HostResponse::Bans { bans } => Ok(bans),
+        HostResponse::Rejected { reason } => Err(anyhow::anyhow!(reason)),
+        _ => Err(anyhow::anyhow!("Unexpected response type")),
+    }
+}
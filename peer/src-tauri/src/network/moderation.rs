@@ -0,0 +1,150 @@
+use iroh::endpoint::Endpoint;
+use iroh::NodeId;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::network::host::{self, BanEntry, HOST_ADDR};
+use crate::state::{Peer, PeerSerializable};
+
+/// How often the ban list is fetched from the host.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Payload of `moderation::banned`, and what `get_ban_status` returns while we are
+/// banned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Banned {
+    pub reason: String,
+    /// Seconds since the unix epoch, `None` for a permanent ban.
+    pub expires_at: Option<u64>,
+}
+
+/// The ban list of the host, which every peer enforces: banned nodes are hidden from
+/// the peer list and their connections to our file protocol are refused.
+///
+/// Without a host there is nothing to enforce and no one is banned.
+///
+/// [`Moderation::shutdown`] stops fetching the ban list.
+#[derive(Debug, Clone)]
+pub struct Moderation {
+    bans: Arc<Mutex<Vec<BanEntry>>>,
+    own_ban: Arc<Mutex<Option<Banned>>>,
+    /// The task fetching the ban list, taken by [`Moderation::shutdown`].
+    refresh: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Moderation {
+    pub fn spawn(endpoint: Endpoint, peers: Arc<Mutex<Vec<Peer>>>, app: AppHandle) -> Self {
+        let moderation = Self {
+            bans: Arc::new(Mutex::new(Vec::new())),
+            own_ban: Arc::new(Mutex::new(None)),
+            refresh: Default::default(),
+        };
+        if HOST_ADDR.is_none() {
+            return moderation;
+        }
+        let this = moderation.clone();
+        let refresh = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                match host::fetch_bans(&endpoint).await {
+                    Ok(bans) => this.apply(bans, endpoint.node_id(), &peers, &app).await,
+                    // Keep enforcing the last known list while the host is unreachable
+                    Err(err) => warn!("Could not fetch the ban list: {err:#}"),
+                }
+            }
+        });
+        *moderation.refresh.lock().expect("poisoned") = Some(refresh);
+        moderation
+    }
+
+    /// Stops fetching the ban list, the last one fetched stays in force.
+    pub fn shutdown(&self) {
+        if let Some(refresh) = self.refresh.lock().expect("poisoned").take() {
+            refresh.abort();
+        }
+    }
+
+    /// The ban applying to `node_id`, or to the nickname it advertises.
+    pub async fn ban_for(&self, node_id: NodeId, nickname: Option<&str>) -> Option<BanEntry> {
+        let now = now();
+        let bans = self.bans.lock().await;
+        bans.iter()
+            .filter(|ban| ban.expires_at.is_none_or(|expires_at| expires_at > now))
+            .find(|ban| {
+                ban.node_id == Some(node_id)
+                    || ban.nickname.as_deref().is_some_and(|banned| {
+                        nickname.is_some_and(|nickname| nickname.eq_ignore_ascii_case(banned))
+                    })
+            })
+            .cloned()
+    }
+
+    /// Our own ban, if the host banned us.
+    pub async fn own_ban(&self) -> Option<Banned> {
+        self.own_ban.lock().await.clone()
+    }
+
+    /// Stores a fresh ban list, drops banned peers with `peer::left` and tells the user
+    /// with `moderation::banned` or `moderation::unbanned` when our own status changes.
+    async fn apply(
+        &self,
+        bans: Vec<BanEntry>,
+        own_node_id: NodeId,
+        peers: &Arc<Mutex<Vec<Peer>>>,
+        app: &AppHandle,
+    ) {
+        *self.bans.lock().await = bans;
+
+        let mut banned_peers = Vec::new();
+        for peer in peers.lock().await.iter() {
+            if self
+                .ban_for(peer.node_addr.node_id, Some(&peer.username))
+                .await
+                .is_some()
+            {
+                banned_peers.push(peer.clone());
+            }
+        }
+        if !banned_peers.is_empty() {
+            let mut peers = peers.lock().await;
+            for peer in banned_peers {
+                peers.retain(|p| p.node_addr.node_id != peer.node_addr.node_id);
+                info!("Hiding banned peer {}", peer.username);
+                let payload: PeerSerializable = peer.into();
+                let _ = app.emit("peer::left", payload);
+            }
+        }
+
+        let own_ban = self.ban_for(own_node_id, None).await.map(|ban| Banned {
+            reason: ban.reason,
+            expires_at: ban.expires_at,
+        });
+        let mut previous = self.own_ban.lock().await;
+        if *previous != own_ban {
+            match &own_ban {
+                Some(banned) => {
+                    warn!("We were banned by the host: {}", banned.reason);
+                    let _ = app.emit("moderation::banned", banned.clone());
+                }
+                None => {
+                    info!("Our ban was lifted");
+                    let _ = app.emit("moderation::unbanned", ());
+                }
+            }
+            *previous = own_ban;
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
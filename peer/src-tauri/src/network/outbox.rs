@@ -0,0 +1,128 @@
+use anyhow::Result;
+use iroh::NodeId;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Listener};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+use crate::network::chat::{ChatHistory, ChatMessage, Direction, StoredMessage};
+use crate::network::protocol::client::send_chat_message;
+use crate::network::sessions::Sessions;
+use crate::state::{Peer, PeerSerializable};
+
+/// How often queued messages to peers that are online are retried, and expired ones
+/// are swept.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delivers chat messages, keeping those for peers that are offline until they show up.
+///
+/// Undelivered messages stay queued in the [`ChatHistory`], so the outbox survives a
+/// restart. Whenever discovery reports a peer with `peer::added` or `peer::back` its
+/// queue is flushed.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    history: ChatHistory,
+    sessions: Sessions,
+    peers: Arc<Mutex<Vec<Peer>>>,
+}
+
+impl Outbox {
+    pub fn spawn(
+        history: ChatHistory,
+        sessions: Sessions,
+        peers: Arc<Mutex<Vec<Peer>>>,
+        app: &AppHandle,
+    ) -> Self {
+        let outbox = Self {
+            history,
+            sessions,
+            peers,
+        };
+
+        let (added_tx, mut added_rx) = mpsc::unbounded_channel::<NodeId>();
+        for event_name in ["peer::added", "peer::back"] {
+            let added_tx = added_tx.clone();
+            app.listen(event_name, move |event| {
+                if let Ok(peer) = serde_json::from_str::<PeerSerializable>(event.payload()) {
+                    let _ = added_tx.send(peer.node_id);
+                }
+            });
+        }
+        let this = outbox.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RETRY_INTERVAL);
+            loop {
+                tokio::select! {
+                    Some(node_id) = added_rx.recv() => this.flush(node_id).await,
+                    _ = interval.tick() => this.flush_all().await,
+                }
+            }
+        });
+        outbox
+    }
+
+    pub fn history(&self) -> &ChatHistory {
+        &self.history
+    }
+
+    /// Queues `text` for `node_id` and tries to deliver it right away.
+    pub async fn send(&self, node_id: NodeId, text: String) -> Result<StoredMessage> {
+        let message = ChatMessage::new(text);
+        let id = message.id;
+        let stored = self.history.record_outgoing(node_id, message).await?;
+        self.flush(node_id).await;
+        Ok(self
+            .history
+            .message(node_id, id, Direction::Outgoing)
+            .await
+            .unwrap_or(stored))
+    }
+
+    /// Delivers the queued messages for `node_id` in order, if the peer is online.
+    async fn flush(&self, node_id: NodeId) {
+        let node_addr = {
+            let peers = self.peers.lock().await;
+            peers
+                .iter()
+                .find(|peer| peer.node_addr.node_id == node_id)
+                .map(|peer| peer.node_addr.clone())
+        };
+        let queued = match self.history.queued(node_id).await {
+            Ok(queued) => queued,
+            Err(err) => {
+                warn!("Could not read outbox for {node_id}: {err:#}");
+                return;
+            }
+        };
+        let Some(node_addr) = node_addr else {
+            return;
+        };
+        for message in queued {
+            let id = message.id;
+            if let Err(err) = send_chat_message(&self.sessions, node_addr.clone(), message).await {
+                // Later messages would arrive out of order, try again next time
+                warn!("Message to {node_id} was not delivered: {err:#}");
+                return;
+            }
+            if let Err(err) = self.history.mark_delivered(node_id, id).await {
+                warn!("Could not mark message to {node_id} as delivered: {err:#}");
+            }
+            info!("Delivered message {id} to {node_id}");
+        }
+    }
+
+    async fn flush_all(&self) {
+        let mut node_ids: Vec<NodeId> = self
+            .history
+            .outbox()
+            .await
+            .into_iter()
+            .map(|queued| queued.node_id)
+            .collect();
+        node_ids.dedup();
+        for node_id in node_ids {
+            self.flush(node_id).await;
+        }
+    }
+}
@@ -1,43 +1,195 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use futures_buffered::BufferedStreamExt;
 use futures_lite::future::Boxed as BoxedFuture;
 use futures_lite::StreamExt;
 use iroh::endpoint::Connection;
 use iroh::endpoint::RecvStream;
 use iroh::endpoint::SendStream;
 use iroh::protocol::ProtocolHandler;
-use iroh::NodeAddr;
-use iroh_blobs::Hash;
+use iroh::{NodeAddr, NodeId};
+use iroh_blobs::format::collection::Collection;
+use iroh_blobs::rpc::client::blobs::AddFileOpts;
+use iroh_blobs::store::ImportMode;
+use iroh_blobs::{BlobFormat, Hash};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
-use tauri::AppHandle;
-use tracing::{info, trace};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::RwLock;
+use tracing::{debug, info, trace, warn};
+
+use crate::access::AccessControl;
+use crate::index::{FileIndex, IndexedFile, SharedRoot};
+use crate::integrity::{self, ShareIssue};
+use crate::network::chat::{self, ChatHistory, ChatMessage};
+use crate::network::favourites::Favourites;
+use crate::network::moderation::Moderation;
+use crate::network::sessions::Sessions;
+use crate::network::slots::{SlotStatus, UploadSlots};
+use crate::network::ticket::{self, is_ticket_tag, ticket_tag};
+use crate::network::transfer::{collection_meta, is_download_tag, ProgressReporter};
+use crate::settings::SharedSettings;
+use crate::utils::{glob_match, scan_named, scan_path, DataSource};
 pub const ALPN: &[u8] = b"hermes/file-protocol/0";
+/// Application error code connections from nodes banned by the host are closed with,
+/// the reason of the ban is sent along.
+pub const BANNED_ERROR_CODE: u32 = 403;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TreeNode {
-    id: String,
-    name: String,
-    hash: String,
-    path: String,
-    size: Option<u64>,
-    modified: Option<DateTime<Utc>>,
-    children: Option<Vec<TreeNode>>,
+    pub id: String,
+    pub name: String,
+    /// Blob hash for files, collection hash for directories.
+    pub hash: String,
+    /// Path relative to the collection root.
+    pub path: String,
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+    pub children: Option<Vec<TreeNode>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProtocolRequestCommand {
     Ping,
-    ListFileRequest { filter: Option<FileFilter> },
+    ListFileRequest {
+        filter: Option<FileFilter>,
+    },
     Quit,
+    /// Lists one page of the direct children of the node with id `parent`, or of the
+    /// top level when `parent` is `None`. Requires protocol version 2.
+    ListChildrenRequest {
+        parent: Option<String>,
+        cursor: u64,
+        limit: u32,
+    },
+    /// Searches shared file names. Requires protocol version 3.
+    SearchRequest {
+        query: String,
+        limit: u32,
+    },
+    /// A direct text message, acknowledged with `ChatAck`. Requires protocol version 4.
+    ChatMessage {
+        message: ChatMessage,
+    },
+    /// Asks where we stand with the peer's upload slots. Requires protocol version 5.
+    SlotStatusRequest,
+    /// Lists the shared files with content `hash`, answered with `SearchResponse`.
+    /// Requires protocol version 7.
+    HashRequest {
+        hash: Hash,
+    },
+    /// Tells the peer where it stands with our upload slots after that changed,
+    /// acknowledged with `SlotStatusAck`. Requires protocol version 8.
+    SlotStatusUpdate {
+        status: SlotStatus,
+    },
 }
+impl ProtocolRequestCommand {
+    /// The protocol version a peer needs for the request, and the feature it belongs to.
+    fn requires(&self) -> Option<(u16, &'static str)> {
+        match self {
+            Self::ListChildrenRequest { .. } => Some((2, "paginated listings")),
+            Self::SearchRequest { .. } => Some((3, "search")),
+            Self::ChatMessage { .. } => Some((4, "chat")),
+            Self::SlotStatusRequest => Some((5, "upload slots")),
+            Self::HashRequest { .. } => Some((7, "hash lookups")),
+            Self::SlotStatusUpdate { .. } => Some((8, "upload slot updates")),
+            Self::Ping | Self::ListFileRequest { .. } | Self::Quit => None,
+        }
+    }
+
+    /// The largest response accepted to the request.
+    fn response_limit(&self) -> u64 {
+        match self {
+            Self::ListFileRequest { .. } => MAX_LISTING_SIZE,
+            _ => MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProtocolResponseCommand {
-    ListFileResponse { files: Vec<TreeNode> },
+    ListFileResponse {
+        files: Vec<TreeNode>,
+    },
     Pong,
+    /// Directories are sent with empty `children`, to be expanded with another request.
+    ListChildrenResponse {
+        children: Vec<TreeNode>,
+        next_cursor: Option<u64>,
+    },
+    SearchResponse {
+        matches: Vec<SearchMatch>,
+    },
+    ChatAck {
+        id: u64,
+    },
+    SlotStatusResponse {
+        status: SlotStatus,
+    },
+    SlotStatusAck,
+}
+
+/// Payload of `slots::status`, sent when a peer reports a new status with its upload
+/// slots.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlotStatusEvent {
+    pub node_id: NodeId,
+    pub status: SlotStatus,
+}
+
+/// Returned by the client functions when the peer speaks a protocol version that is
+/// too old for the request.
+#[derive(Debug, Clone)]
+pub struct UnsupportedRequest {
+    pub feature: &'static str,
+}
+
+impl std::fmt::Display for UnsupportedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer does not support {}", self.feature)
+    }
+}
+
+impl std::error::Error for UnsupportedRequest {}
+
+/// Returned by [`recv_msg`] when the length prefix of a message exceeds the limit.
+#[derive(Debug, Clone)]
+pub struct MessageTooLarge {
+    pub len: u64,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Message of {} bytes exceeds the limit of {} bytes",
+            self.len, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchMatch {
+    pub name: String,
+    pub path: String,
+    pub size: Option<u64>,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildrenPage {
+    pub children: Vec<TreeNode>,
+    /// Cursor of the next page, `None` on the last page.
+    pub next_cursor: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,6 +201,68 @@ pub struct FileFilter {
     pub depth: Option<usize>,
 }
 
+impl FileFilter {
+    /// Whether the filter only limits the depth, which the tree builder handles itself.
+    fn only_depth(&self) -> bool {
+        self.name.is_none() && self.size_range.is_none() && self.is_dir.is_none()
+    }
+
+    /// Filters a files tree.
+    ///
+    /// `name` is matched case-insensitively, as a glob if it contains `*` or `?` and
+    /// as a substring otherwise. `size_range` only applies to files. Directories that
+    /// neither match themselves nor contain a match are pruned, and the result is cut
+    /// off at `depth`.
+    pub fn apply(&self, nodes: Vec<TreeNode>) -> Vec<TreeNode> {
+        self.filter_level(nodes, 0)
+    }
+
+    fn filter_level(&self, nodes: Vec<TreeNode>, level: usize) -> Vec<TreeNode> {
+        nodes
+            .into_iter()
+            .filter_map(|mut node| match node.children.take() {
+                Some(children) => {
+                    let children = self.filter_level(children, level + 1);
+                    if children.is_empty() && !self.matches(&node) {
+                        return None;
+                    }
+                    let truncated = self.depth.is_some_and(|depth| level >= depth);
+                    node.children = Some(if truncated { Vec::new() } else { children });
+                    Some(node)
+                }
+                None => self.matches(&node).then_some(node),
+            })
+            .collect()
+    }
+
+    fn matches(&self, node: &TreeNode) -> bool {
+        let is_dir = node.children.is_some();
+        if self.is_dir.is_some_and(|want_dir| want_dir != is_dir) {
+            return false;
+        }
+        if let (Some((min, max)), false) = (self.size_range, is_dir) {
+            if !node.size.is_some_and(|size| (min..=max).contains(&size)) {
+                return false;
+            }
+        }
+        match &self.name {
+            Some(pattern) => name_matches(pattern, &node.name),
+            // A directory only matches on its own when asked for by name or type
+            None => !is_dir || self.is_dir == Some(true),
+        }
+    }
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    if pattern.contains(['*', '?']) {
+        glob_match(&pattern, &name)
+    } else {
+        name.contains(&pattern)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct VersionMessage<T> {
     version: u16,
@@ -69,18 +283,37 @@ pub struct ProgressMessage {
     pub percentage: f32,
 }
 
-const CURRENT_PROTOCOL_VERSION: u16 = 1;
-const SUPPORTED_VERSIONS: [u16; 1] = [CURRENT_PROTOCOL_VERSION];
-type BlobsClient = iroh_blobs::rpc::client::blobs::Client<
-    quic_rpc::transport::flume::FlumeConnector<
-        iroh_blobs::rpc::proto::Response,
-        iroh_blobs::rpc::proto::Request,
-    >,
+const CURRENT_PROTOCOL_VERSION: u16 = 8;
+const SUPPORTED_VERSIONS: [u16; 8] = [1, 2, 3, 4, 5, 6, 7, CURRENT_PROTOCOL_VERSION];
+/// From this version on a connection serves requests on any number of bi-streams, and
+/// only the first one negotiates the version.
+pub(crate) const SESSION_PROTOCOL_VERSION: u16 = 6;
+/// Largest page a peer may ask for with `ListChildrenRequest`.
+const MAX_PAGE_SIZE: u32 = 1000;
+/// Largest number of matches returned for a `SearchRequest`.
+const MAX_SEARCH_RESULTS: u32 = 500;
+/// Upper bound on the length prefix of a message, so a peer cannot make us allocate
+/// arbitrary amounts of memory.
+const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+/// Like [`MAX_MESSAGE_SIZE`], but for the `ListFileResponse` to a full listing, which
+/// holds every shared file at once.
+const MAX_LISTING_SIZE: u64 = 256 * 1024 * 1024;
+type BlobsConnector = quic_rpc::transport::flume::FlumeConnector<
+    iroh_blobs::rpc::proto::Response,
+    iroh_blobs::rpc::proto::Request,
 >;
+pub type BlobsClient = iroh_blobs::rpc::client::blobs::Client<BlobsConnector>;
+type Batch = iroh_blobs::rpc::client::blobs::Batch<BlobsConnector>;
 #[derive(Debug, Clone)]
 pub struct FileProtocol {
     blobs_client: BlobsClient,
     app_handle: AppHandle,
+    index: Arc<RwLock<FileIndex>>,
+    chat: ChatHistory,
+    settings: SharedSettings,
+    moderation: Moderation,
+    access: AccessControl,
+    slots: UploadSlots,
 }
 
 impl ProtocolHandler for FileProtocol {
@@ -88,114 +321,327 @@ impl ProtocolHandler for FileProtocol {
         let this = self.clone();
         Box::pin(async move {
             let node_id = connection.remote_node_id()?;
+            if let Some(ban) = this.moderation.ban_for(node_id, None).await {
+                info!("Refusing connection from banned node {node_id}");
+                connection.close(BANNED_ERROR_CODE.into(), ban.reason.as_bytes());
+                return Ok(());
+            }
             trace!("accepted connection from {node_id}");
             let (mut send, mut recv) = connection.accept_bi().await?;
+            let version = negotiate_version(&mut send, &mut recv, ConnectionRole::Listener).await?;
+            if version < SESSION_PROTOCOL_VERSION {
+                return this.serve(node_id, send, recv).await;
+            }
 
-            // Could be used to handle different protocol versions in the future
-            let _negotiated_version =
-                negotiate_version(&mut send, &mut recv, ConnectionRole::Listener).await?;
-
-            loop {
-                let command: ProtocolRequestCommand = recv_msg(&mut recv).await?;
-                match command {
-                    ProtocolRequestCommand::Ping => {
-                        info!("Received ping, sending pong.");
-                        let response = ProtocolResponseCommand::Pong;
-                        send_msg(&mut send, &response).await?;
-                    }
-                    ProtocolRequestCommand::ListFileRequest { filter } => {
-                        let files = if let Some(filter) = filter {
-                            let uploaded_files = this.get_files_tree(filter.depth).await?;
-                            // TODO implement filtering logic
-                            uploaded_files
-                        } else {
-                            this.get_files_tree(None).await?
-                        };
-                        let response = ProtocolResponseCommand::ListFileResponse { files };
-                        send_msg(&mut send, &response).await?;
-                    }
-                    ProtocolRequestCommand::Quit => {
-                        trace!("Received quit command, closing connection.");
-                        break;
-                    }
+            // The peer keeps the connection as a session and opens a stream per request
+            let first = this.clone();
+            tokio::spawn(async move { first.serve_logged(node_id, send, recv).await });
+            while let Ok((send, recv)) = connection.accept_bi().await {
+                // Bans issued while the session is open apply to its next request
+                if let Some(ban) = this.moderation.ban_for(node_id, None).await {
+                    info!("Closing session of banned node {node_id}");
+                    connection.close(BANNED_ERROR_CODE.into(), ban.reason.as_bytes());
+                    break;
                 }
+                let this = this.clone();
+                tokio::spawn(async move { this.serve_logged(node_id, send, recv).await });
             }
-
+            trace!("Session with {node_id} ended");
             Ok(())
         })
     }
 }
 
 impl FileProtocol {
-    pub fn new(blobs_client: BlobsClient, app: AppHandle) -> Self {
-        Self {
+    /// Answers the requests on one stream until the peer sends `Quit`.
+    async fn serve(
+        &self,
+        node_id: NodeId,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        loop {
+            let command: ProtocolRequestCommand = recv_msg(&mut recv).await?;
+            match command {
+                ProtocolRequestCommand::Ping => {
+                    info!("Received ping, sending pong.");
+                    let response = ProtocolResponseCommand::Pong;
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::ListFileRequest { filter } => {
+                    let files = if !self.share_listing().await {
+                        Vec::new()
+                    } else {
+                        match filter {
+                            Some(filter) if filter.only_depth() => {
+                                self.get_files_tree(Some(node_id), filter.depth).await?
+                            }
+                            Some(filter) => {
+                                filter.apply(self.get_files_tree(Some(node_id), None).await?)
+                            }
+                            None => self.get_files_tree(Some(node_id), None).await?,
+                        }
+                    };
+                    let response = ProtocolResponseCommand::ListFileResponse { files };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::ListChildrenRequest {
+                    parent,
+                    cursor,
+                    limit,
+                } => {
+                    let page = if self.share_listing().await {
+                        self.get_children_page(Some(node_id), parent.as_deref(), cursor, limit)
+                            .await?
+                    } else {
+                        ChildrenPage {
+                            children: Vec::new(),
+                            next_cursor: None,
+                        }
+                    };
+                    let response = ProtocolResponseCommand::ListChildrenResponse {
+                        children: page.children,
+                        next_cursor: page.next_cursor,
+                    };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::SearchRequest { query, limit } => {
+                    let matches = if self.share_listing().await {
+                        self.search(Some(node_id), &query, limit).await?
+                    } else {
+                        Vec::new()
+                    };
+                    let response = ProtocolResponseCommand::SearchResponse { matches };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::ChatMessage { message } => {
+                    // The sender keeps the message queued until it expires
+                    anyhow::ensure!(
+                        self.settings.lock().await.privacy.accept_messages,
+                        "Not accepting messages"
+                    );
+                    anyhow::ensure!(
+                        message.text.len() <= chat::MAX_MESSAGE_LEN,
+                        "Chat message exceeds {} bytes",
+                        chat::MAX_MESSAGE_LEN
+                    );
+                    let id = message.id;
+                    self.chat.record_incoming(node_id, message).await?;
+                    let response = ProtocolResponseCommand::ChatAck { id };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::HashRequest { hash } => {
+                    let matches = if self.share_listing().await {
+                        self.files_with_hash(Some(node_id), &hash).await
+                    } else {
+                        Vec::new()
+                    };
+                    let response = ProtocolResponseCommand::SearchResponse { matches };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::SlotStatusRequest => {
+                    let status = self.slots.status(node_id);
+                    let response = ProtocolResponseCommand::SlotStatusResponse { status };
+                    send_msg(&mut send, &response).await?;
+                }
+                ProtocolRequestCommand::SlotStatusUpdate { status } => {
+                    let payload = SlotStatusEvent { node_id, status };
+                    let _ = self.app_handle.emit("slots::status", payload);
+                    send_msg(&mut send, &ProtocolResponseCommand::SlotStatusAck).await?;
+                }
+                ProtocolRequestCommand::Quit => {
+                    trace!("Received quit command, closing stream.");
+                    break;
+                }
+            }
+        }
+        send.finish()?;
+        Ok(())
+    }
+
+    async fn serve_logged(&self, node_id: NodeId, send: SendStream, recv: RecvStream) {
+        if let Err(err) = self.serve(node_id, send, recv).await {
+            debug!("Stream from {node_id} failed: {err:#}");
+        }
+    }
+}
+
+impl FileProtocol {
+    /// Creates the protocol handler, loading the index of our shares or rebuilding it
+    /// from the blob store if there is none.
+    pub async fn new(
+        blobs_client: BlobsClient,
+        settings: SharedSettings,
+        moderation: Moderation,
+        slots: UploadSlots,
+        favourites: Favourites,
+        app: AppHandle,
+    ) -> Result<Self> {
+        // The index may be missing shares tagged before a crash, or list shares that
+        // were removed since it was saved
+        let tags = share_tags(&blobs_client).await?;
+        let mut index = match FileIndex::load() {
+            Some(index) if index.matches(&tags) => index,
+            _ => {
+                info!("Rebuilding file index from the blob store");
+                let index = build_index(&blobs_client, tags).await?;
+                index.save().await?;
+                index
+            }
+        };
+        index_tickets(&blobs_client, &mut index).await?;
+        Ok(Self {
             blobs_client,
+            chat: ChatHistory::new(app.clone()),
             app_handle: app,
+            index: Arc::new(RwLock::new(index)),
+            settings,
+            moderation,
+            access: AccessControl::load(favourites),
+            slots,
+        })
+    }
+
+    /// Whether peers may list and search our shares, see [`PrivacySettings`].
+    ///
+    /// [`PrivacySettings`]: crate::settings::PrivacySettings
+    async fn share_listing(&self) -> bool {
+        self.settings.lock().await.privacy.share_listing
+    }
+
+    pub fn blobs_client(&self) -> &BlobsClient {
+        &self.blobs_client
+    }
+
+    pub fn chat(&self) -> &ChatHistory {
+        &self.chat
+    }
+
+    /// Tags of all our shares, the absolute paths they were shared from.
+    pub async fn shared_tags(&self) -> Vec<String> {
+        let index = self.index.read().await;
+        index.roots().iter().map(|root| root.tag.clone()).collect()
+    }
+
+    pub fn access(&self) -> &AccessControl {
+        &self.access
+    }
+
+    /// Whether `node_id` may fetch the blob `hash`, `None` if it is not ours to serve.
+    ///
+    /// Files and the collections of our shares are served if one of the shares holding
+    /// them is visible to the node. Collections created for tickets are served to
+    /// everyone, their entries are files of our shares.
+    pub async fn may_fetch(&self, node_id: NodeId, hash: &Hash) -> Option<bool> {
+        let index = self.index.read().await;
+        if index.is_ticket(hash) {
+            return Some(true);
+        }
+        let holding = index.holding(hash);
+        if holding.is_empty() {
+            return None;
         }
+        Some(!self.access.visible(holding, Some(node_id)).await.is_empty())
     }
 
-    pub async fn get_files_tree(&self, depth: Option<usize>) -> Result<Vec<TreeNode>> {
+    /// The tree of the shares visible to `viewer`, or of all shares for ourselves (`None`).
+    pub async fn get_files_tree(
+        &self,
+        viewer: Option<NodeId>,
+        depth: Option<usize>,
+    ) -> Result<Vec<TreeNode>> {
         let mut res = Vec::new();
-
-        let mut tag_stream = self.blobs_client.tags().list().await?;
-        // stream
-        while let Some(tag) = tag_stream.next().await {
-            let tag_info = tag?;
-            let root_path = tag_info.name.to_string();
-            let collection = self.blobs_client.get_collection(tag_info.hash).await?;
-
-            for (name, hash) in collection.iter() {
-                let path = PathBuf::from(&name);
-                let mut node = &mut res;
-                let len = path.iter().count();
-                let mut cur_path = PathBuf::new();
-                'inner: for (i, p) in path.iter().enumerate() {
-                    cur_path.push(p);
-                    let absolute_path =
-                        root_path.clone() + "/" + cur_path.to_str().unwrap_or("unknown");
-                    let id = Hash::new(&absolute_path);
-
-                    let child_index = node.iter().position(|c: &TreeNode| c.id == id.to_string());
-
-                    if let Some(idx) = child_index {
-                        node = node[idx]
-                            .children
-                            .as_mut()
-                            .expect("Node should have children");
-                    } else {
-                        let size = self.blobs_client.read(*hash).await?.size();
-                        let children = if i == len - 1 { None } else { Some(Vec::new()) };
-                        let new_node = TreeNode {
-                            id: id.to_string(),
-                            name: p.to_string_lossy().to_string(),
-                            hash: hash.to_string(),
-                            path: cur_path.to_string_lossy().to_string(),
-                            size: Some(size),
-                            children,
-                            modified: None,
-                        };
-                        node.push(new_node);
-                        if i == len - 1 {
-                            break 'inner;
-                        }
-                        node = node
-                            .last_mut()
-                            .expect("Should exist see above line")
-                            .children
-                            .as_mut()
-                            .expect("Node should have children");
-                    }
-                    if let Some(max_depth) = depth {
-                        if i >= max_depth {
-                            break 'inner;
-                        }
-                    }
-                }
-            }
+        let index = self.index.read().await;
+        for root in self.access.visible(index.roots(), viewer).await {
+            res.extend(root.tree(depth));
         }
         Ok(res)
     }
 
+    /// Returns one page of the direct children of the node with id `parent`, or of the
+    /// top level nodes. Directories come first, then files, each sorted by name.
+    pub async fn get_children_page(
+        &self,
+        viewer: Option<NodeId>,
+        parent: Option<&str>,
+        cursor: u64,
+        limit: u32,
+    ) -> Result<ChildrenPage> {
+        let index = self.index.read().await;
+        let visible: HashSet<&str> = self
+            .access
+            .visible(index.roots(), viewer)
+            .await
+            .into_iter()
+            .map(|root| root.tag.as_str())
+            .collect();
+        let children = index
+            .children(parent, |root| visible.contains(root.tag.as_str()))
+            .context("No such directory")?;
+
+        let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+        let start = usize::try_from(cursor)
+            .unwrap_or(usize::MAX)
+            .min(children.len());
+        let end = start.saturating_add(limit).min(children.len());
+        let next_cursor = (end < children.len()).then_some(end as u64);
+        let children = children[start..end]
+            .iter()
+            .map(|&node| node.clone())
+            .collect();
+        Ok(ChildrenPage {
+            children,
+            next_cursor,
+        })
+    }
+
+    /// Returns up to `limit` shared files whose name matches every word of `query`
+    /// as a case-insensitive prefix.
+    pub async fn search(
+        &self,
+        viewer: Option<NodeId>,
+        query: &str,
+        limit: u32,
+    ) -> Result<Vec<SearchMatch>> {
+        let index = self.index.read().await;
+        let visible: HashSet<&str> = self
+            .access
+            .visible(index.roots(), viewer)
+            .await
+            .into_iter()
+            .map(|root| root.tag.as_str())
+            .collect();
+        let limit = limit.min(MAX_SEARCH_RESULTS) as usize;
+        Ok(index
+            .search(query, limit, |root| visible.contains(root.tag.as_str()))
+            .into_iter()
+            .map(|file| SearchMatch {
+                name: file.name().to_owned(),
+                path: file.path.clone(),
+                size: Some(file.size),
+                hash: file.hash.to_string(),
+            })
+            .collect())
+    }
+
+    /// The shared files with content `hash` that `viewer` may see.
+    pub async fn files_with_hash(&self, viewer: Option<NodeId>, hash: &Hash) -> Vec<SearchMatch> {
+        let index = self.index.read().await;
+        self.access
+            .visible(index.roots(), viewer)
+            .await
+            .into_iter()
+            .flat_map(|root| root.files.iter())
+            .filter(|file| &file.hash == hash)
+            .map(|file| SearchMatch {
+                name: file.name().to_owned(),
+                path: file.path.clone(),
+                size: Some(file.size),
+                hash: file.hash.to_string(),
+            })
+            .collect()
+    }
+
     /// Import from a file or directory into the database.
     ///
     /// The returned tag always refers to a collection. If the input is a file, this
@@ -203,56 +649,374 @@ impl FileProtocol {
     ///
     /// If the input is a directory, the collection contains all the files in the
     /// directory.
+    ///
+    /// Progress is reported to the frontend as `transfer::progress` events while the
+    /// files are hashed.
     pub async fn import(&self, path: impl AsRef<Path>) -> Result<(iroh_blobs::Tag, Hash)> {
-        let batch = self.blobs_client.batch().await?;
         let path = path.as_ref();
-        let temp_tag = batch
-            .add_dir_with_opts(
-                path.into(),
-                iroh_blobs::rpc::client::blobs::AddDirOpts {
-                    import_mode: iroh_blobs::store::ImportMode::TryReference,
-                    wrap: iroh_blobs::rpc::client::blobs::WrapOption::Wrap { name: None },
-                    io_parallelism: 4,
-                },
-            )
-            .await
-            .context("Failed to import file or directory")?;
-        let tag = iroh_blobs::Tag::from(path.to_str().context("Not a valid UTF-8 path")?);
+        let mut progress =
+            ProgressReporter::new(self.app_handle.clone(), path.display().to_string());
+        let result = self.import_with_progress(path, &mut progress).await;
+        match &result {
+            Ok(_) => progress.completed(),
+            Err(err) => progress.failed(err),
+        }
+        result
+    }
+
+    async fn import_with_progress(
+        &self,
+        path: &Path,
+        progress: &mut ProgressReporter,
+    ) -> Result<(iroh_blobs::Tag, Hash)> {
+        let batch = self.blobs_client.batch().await?;
+        let sources = {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || scan_path(&path)).await??
+        };
+        progress.set_total(sources.iter().map(|source| source.size).sum());
+        let (files, file_tags) = self.import_sources(&batch, sources, progress).await?;
+        let result = self.persist_share(batch, path, files).await;
+        drop(file_tags);
+        result
+    }
+
+    /// Hashes the files of `sources` into `batch`, returns their index entries and the
+    /// temp tags that keep them alive until the collection is persisted.
+    async fn import_sources(
+        &self,
+        batch: &Batch,
+        sources: Vec<DataSource>,
+        progress: &mut ProgressReporter,
+    ) -> Result<(Vec<IndexedFile>, Vec<iroh_blobs::TempTag>)> {
+        let io_parallelism = self.settings.lock().await.io_parallelism;
+        let mut imports = futures_lite::stream::iter(sources)
+            .map(|source| async move {
+                let opts = AddFileOpts {
+                    import_mode: ImportMode::TryReference,
+                    format: BlobFormat::Raw,
+                };
+                let (temp_tag, size) = batch.add_file_with_opts(source.path.clone(), opts).await?;
+                anyhow::Ok((source, temp_tag, size))
+            })
+            .buffered_ordered(io_parallelism);
+
+        let mut files = Vec::new();
+        let mut file_tags = Vec::new();
+        let mut bytes_done = 0;
+        while let Some(import) = imports.next().await {
+            let (source, temp_tag, size) = import.context("Failed to import file or directory")?;
+            bytes_done += size;
+            progress.update(bytes_done);
+            files.push(IndexedFile {
+                path: source.name,
+                hash: *temp_tag.hash(),
+                size,
+                modified: source.modified,
+            });
+            file_tags.push(temp_tag);
+        }
+        Ok((files, file_tags))
+    }
+
+    /// Stores `files` as the collection of the share at `path` and indexes it.
+    async fn persist_share(
+        &self,
+        batch: Batch,
+        path: &Path,
+        files: Vec<IndexedFile>,
+    ) -> Result<(iroh_blobs::Tag, Hash)> {
+        let collection: Collection = files
+            .iter()
+            .map(|file| (file.path.clone(), file.hash))
+            .collect();
+        let temp_tag = batch.add_collection(collection).await?;
+        let tag_name = path.to_str().context("Not a valid UTF-8 path")?;
+        let tag = iroh_blobs::Tag::from(tag_name);
         let hash = *temp_tag.hash();
         batch.persist_to(temp_tag, tag.clone()).await?;
         drop(batch);
+        let meta = collection_meta(&self.blobs_client, hash).await?;
+
+        let mut index = self.index.write().await;
+        index.upsert(SharedRoot {
+            tag: tag_name.to_owned(),
+            hash,
+            meta,
+            files,
+        });
+        index.save().await?;
         info!("Imported {} with hash {}", path.display(), hash);
         Ok((tag, hash))
     }
 
+    /// Checks that the files we share by reference still exist on disk, unchanged.
+    ///
+    /// With `deep` every file that looks unchanged is also hashed again, which catches
+    /// edits that kept the size and modification time but takes a while.
+    pub async fn check_sources(&self, deep: bool) -> Vec<ShareIssue> {
+        let roots = self.index.read().await.roots().to_vec();
+        tokio::task::spawn_blocking(move || integrity::check_roots(&roots, deep))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Imports the share under `tag` again, picking up changed and dropping deleted files.
+    pub async fn rescan(&self, tag: &str) -> Result<Hash> {
+        anyhow::ensure!(
+            self.index
+                .read()
+                .await
+                .roots()
+                .iter()
+                .any(|root| root.tag == tag),
+            "{tag} is not shared"
+        );
+        let path = PathBuf::from(tag);
+        anyhow::ensure!(
+            path.exists(),
+            "{tag} no longer exists, remove the share instead"
+        );
+        let (_, hash) = self.import(path).await?;
+        Ok(hash)
+    }
+
+    /// Imports the files at and below `paths` of the share under `tag` again, keeping
+    /// the entries of all other files.
+    ///
+    /// Files that were deleted are dropped, and only new files and files whose size or
+    /// modification time changed are hashed.
+    pub async fn update_paths(&self, tag: &str, paths: &[PathBuf]) -> Result<Hash> {
+        let root = self
+            .index
+            .read()
+            .await
+            .roots()
+            .iter()
+            .find(|root| root.tag == tag)
+            .cloned()
+            .with_context(|| format!("{tag} is not shared"))?;
+        let share_path = PathBuf::from(tag);
+        anyhow::ensure!(
+            share_path.exists(),
+            "{tag} no longer exists, remove the share instead"
+        );
+        // Collection entries are named relative to the parent of the shared path
+        let parent = share_path
+            .parent()
+            .context("Cannot share the root directory")?;
+        let changed: Vec<(PathBuf, String)> = paths
+            .iter()
+            .filter_map(|path| {
+                let name = path.strip_prefix(parent).ok()?.to_str()?.to_owned();
+                Some((path.clone(), name))
+            })
+            .collect();
+
+        let (mut files, previous): (Vec<IndexedFile>, Vec<IndexedFile>) = root
+            .files
+            .into_iter()
+            .partition(|file| !changed.iter().any(|(_, name)| is_below(&file.path, name)));
+        let previous: HashMap<String, IndexedFile> = previous
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect();
+        let sources = tokio::task::spawn_blocking(move || {
+            let mut sources = BTreeMap::new();
+            for (path, name) in changed {
+                if !path.exists() {
+                    continue;
+                }
+                for source in scan_named(&path, name)? {
+                    sources.insert(source.name.clone(), source);
+                }
+            }
+            anyhow::Ok(sources)
+        })
+        .await??;
+
+        let mut stale = Vec::new();
+        for source in sources.into_values() {
+            match previous.get(&source.name) {
+                Some(file) if file.size == source.size && file.modified == source.modified => {
+                    files.push(file.clone());
+                }
+                _ => stale.push(source),
+            }
+        }
+
+        let mut progress = ProgressReporter::new(self.app_handle.clone(), tag.to_owned());
+        progress.set_total(stale.iter().map(|source| source.size).sum());
+        let result = async {
+            let batch = self.blobs_client.batch().await?;
+            let (imported, file_tags) = self.import_sources(&batch, stale, &mut progress).await?;
+            files.extend(imported);
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            let (_, hash) = self.persist_share(batch, &share_path, files).await?;
+            drop(file_tags);
+            anyhow::Ok(hash)
+        }
+        .await;
+        match &result {
+            Ok(_) => progress.completed(),
+            Err(err) => progress.failed(err),
+        }
+        result
+    }
+
+    /// Returns the hash of a collection holding `node` and everything below it, named
+    /// relative to the node's parent, creating the collection if needed.
+    pub async fn ticket_collection(&self, node: &TreeNode) -> Result<Hash> {
+        let index = self.index.read().await;
+        let root = index
+            .root_containing(&node.hash)
+            .with_context(|| format!("{} is not shared", node.name))?;
+        let node_path = Path::new(&node.path);
+        // A shared root already is such a collection
+        if node.children.is_some() && node_path.components().count() == 1 {
+            return Ok(root.hash);
+        }
+
+        let parent = node_path.parent().unwrap_or_else(|| Path::new(""));
+        let mut collection = Collection::default();
+        for file in &root.files {
+            let path = Path::new(&file.path);
+            if !path.starts_with(node_path) {
+                continue;
+            }
+            let name = path
+                .strip_prefix(parent)?
+                .to_str()
+                .context("Not a valid UTF-8 path")?;
+            collection.push(name.to_owned(), file.hash);
+        }
+        anyhow::ensure!(!collection.is_empty(), "{} has no files", node.name);
+        let share_tag = root.tag.clone();
+        drop(index);
+
+        let batch = self.blobs_client.batch().await?;
+        let temp_tag = batch.add_collection(collection).await?;
+        let hash = *temp_tag.hash();
+        batch
+            .persist_to(temp_tag, ticket_tag(&share_tag, &hash))
+            .await?;
+        let meta = collection_meta(&self.blobs_client, hash).await?;
+        self.index.write().await.add_ticket(&share_tag, hash, meta);
+        Ok(hash)
+    }
+
     pub async fn clear_all_files(&mut self) -> Result<()> {
-        self.blobs_client.tags().delete_all().await?;
+        let mut index = self.index.write().await;
+        for root in index.roots() {
+            self.blobs_client
+                .tags()
+                .delete(iroh_blobs::Tag::from(root.tag.as_str()))
+                .await?;
+            ticket::delete_tickets(&self.blobs_client, &root.tag).await?;
+            self.access.remove(&root.tag).await?;
+        }
+        index.clear();
+        index.save().await?;
         Ok(())
     }
 
     pub async fn remove_by_hash(&mut self, node_hash: &str) -> Result<()> {
-        let mut tag_stream = self.blobs_client.tags().list().await?;
-        while let Some(tag) = tag_stream.next().await {
-            let tag_info = tag?;
-            let collection = self.blobs_client.get_collection(tag_info.hash).await?;
-            for (_name, file_hash) in collection.iter() {
-                if file_hash.to_string() == node_hash {
-                    self.blobs_client.tags().delete(tag_info.name.clone()).await?;
-                    return Ok(());
-                }
-            }
+        let mut index = self.index.write().await;
+        let tag = index
+            .root_containing(node_hash)
+            .map(|root| root.tag.clone())
+            .with_context(|| format!("No tag found for file hash {}", node_hash))?;
+        self.blobs_client
+            .tags()
+            .delete(iroh_blobs::Tag::from(tag.as_str()))
+            .await?;
+        ticket::delete_tickets(&self.blobs_client, &tag).await?;
+        self.access.remove(&tag).await?;
+        index.remove(&tag);
+        index.save().await?;
+        Ok(())
+    }
+}
+
+/// Whether the collection entry `path` is `name` or lies below it.
+fn is_below(path: &str, name: &str) -> bool {
+    path.strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Records the ticket collections in the store with the shares they were created for.
+async fn index_tickets(blobs_client: &BlobsClient, index: &mut FileIndex) -> Result<()> {
+    let mut tickets = Vec::new();
+    let mut tag_stream = blobs_client.tags().list().await?;
+    while let Some(tag) = tag_stream.next().await {
+        let tag_info = tag?;
+        if let Some(share_tag) = ticket::ticket_share(&tag_info.name) {
+            tickets.push((share_tag, tag_info.hash));
         }
-        Err(anyhow::anyhow!("No tag found for file hash {}", node_hash))
     }
+    for (share_tag, hash) in tickets {
+        let meta = collection_meta(blobs_client, hash).await?;
+        index.add_ticket(&share_tag, hash, meta);
+    }
+    Ok(())
+}
 
+/// The tags of our shares with the hashes of their collections, download and ticket
+/// tags left out.
+async fn share_tags(blobs_client: &BlobsClient) -> Result<BTreeMap<String, Hash>> {
+    let mut tags = BTreeMap::new();
+    let mut tag_stream = blobs_client.tags().list().await?;
+    while let Some(tag) = tag_stream.next().await {
+        let tag_info = tag?;
+        if is_download_tag(&tag_info.name) || is_ticket_tag(&tag_info.name) {
+            continue;
+        }
+        tags.insert(tag_info.name.to_string(), tag_info.hash);
+    }
+    Ok(tags)
 }
 
-enum ConnectionRole {
+/// Rebuilds the index of the shares `tags` by walking their collections in the store.
+async fn build_index(
+    blobs_client: &BlobsClient,
+    tags: BTreeMap<String, Hash>,
+) -> Result<FileIndex> {
+    let mut roots = Vec::new();
+    for (root_tag, root_hash) in tags {
+        // Collection entries are named relative to the parent of the shared path
+        let parent = Path::new(&root_tag).parent().map(Path::to_path_buf);
+        let collection = blobs_client.get_collection(root_hash).await?;
+        let mut files = Vec::new();
+        for (name, hash) in collection.iter() {
+            let size = blobs_client.read(*hash).await?.size();
+            let modified = parent
+                .as_ref()
+                .and_then(|parent| std::fs::metadata(parent.join(name)).ok())
+                .and_then(|metadata| metadata.modified().ok())
+                .map(DateTime::from);
+            files.push(IndexedFile {
+                path: name.clone(),
+                hash: *hash,
+                size,
+                modified,
+            });
+        }
+        roots.push(SharedRoot {
+            tag: root_tag,
+            hash: root_hash,
+            meta: collection_meta(blobs_client, root_hash).await?,
+            files,
+        });
+    }
+    Ok(FileIndex::new(roots))
+}
+
+pub(crate) enum ConnectionRole {
     Listener,
     Initiator,
 }
 // During connection setup
-async fn negotiate_version(
+pub(crate) async fn negotiate_version(
     send: &mut SendStream,
     recv: &mut RecvStream,
     role: ConnectionRole,
@@ -293,12 +1057,23 @@ async fn negotiate_version(
 }
 
 pub async fn recv_msg<T>(recv: &mut RecvStream) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    recv_msg_limited(recv, MAX_MESSAGE_SIZE).await
+}
+
+/// Like [`recv_msg`], but accepts messages of up to `limit` bytes.
+async fn recv_msg_limited<T>(recv: &mut RecvStream, limit: u64) -> Result<T>
 where
     T: DeserializeOwned,
 {
     let mut incoming_len = [0u8; 8];
     recv.read_exact(&mut incoming_len).await?;
     let len = u64::from_le_bytes(incoming_len);
+    if len > limit {
+        return Err(MessageTooLarge { len, limit }.into());
+    }
 
     let mut buffer = vec![0u8; len as usize];
     recv.read_exact(&mut buffer).await?;
@@ -306,7 +1081,7 @@ where
     Ok(msg)
 }
 
-async fn send_msg<T>(send: &mut SendStream, msg: &T) -> Result<()>
+pub(crate) async fn send_msg<T>(send: &mut SendStream, msg: &T) -> Result<()>
 where
     T: Serialize,
 {
@@ -318,42 +1093,287 @@ where
 }
 
 ///  Contains client-side functions for interacting with remote peers.
+///
+/// Requests go through the peer's session, see [`Sessions`].
 pub mod client {
     use super::*;
-    pub async fn ping_peer(
-        endpoint: &iroh::endpoint::Endpoint,
-        node_addr: impl Into<NodeAddr>,
-    ) -> Result<()> {
+
+    /// Sends `request` on a new stream to the peer and returns its response.
+    async fn request(
+        sessions: &Sessions,
+        node_addr: NodeAddr,
+        request: &ProtocolRequestCommand,
+    ) -> Result<ProtocolResponseCommand> {
+        let mut stream = sessions.open(node_addr).await?;
+        if let Some((version, feature)) = request.requires() {
+            if stream.version < version {
+                send_msg(&mut stream.send, &ProtocolRequestCommand::Quit).await?;
+                stream.send.finish()?;
+                return Err(UnsupportedRequest { feature }.into());
+            }
+        }
+        send_msg(&mut stream.send, request).await?;
+        let response = recv_msg_limited(&mut stream.recv, request.response_limit()).await?;
+        // Only ends the stream, the session stays open for the next request
+        send_msg(&mut stream.send, &ProtocolRequestCommand::Quit).await?;
+        stream.send.finish()?;
+        Ok(response)
+    }
+
+    pub async fn ping_peer(sessions: &Sessions, node_addr: impl Into<NodeAddr>) -> Result<()> {
         let node_addr = node_addr.into();
-        let conn = endpoint.connect(node_addr.clone(), ALPN).await?;
-
-        let (mut send, mut recv) = conn.open_bi().await?;
-        negotiate_version(&mut send, &mut recv, ConnectionRole::Initiator).await?;
-        send_msg(&mut send, &ProtocolRequestCommand::Ping).await?;
-        info!("Sent ping to {}", &node_addr.node_id);
-        let recv: ProtocolResponseCommand = recv_msg(&mut recv).await?;
-        info!("Received response: {:?}", recv);
+        let node_id = node_addr.node_id;
+        let response = request(sessions, node_addr, &ProtocolRequestCommand::Ping).await?;
+        info!("Sent ping to {node_id}, received response: {response:?}");
         Ok(())
     }
 
     pub async fn list_remote_files(
-        endpoint: &iroh::endpoint::Endpoint,
+        sessions: &Sessions,
         node_addr: impl Into<NodeAddr>,
         filter: Option<FileFilter>,
     ) -> Result<Vec<TreeNode>> {
-        let node_addr = node_addr.into();
-        let conn = endpoint.connect(node_addr.clone(), ALPN).await?;
-        let (mut send, mut recv) = conn.open_bi().await?;
-        negotiate_version(&mut send, &mut recv, ConnectionRole::Initiator).await?;
+        let request_command = ProtocolRequestCommand::ListFileRequest { filter };
+        let response = request(sessions, node_addr.into(), &request_command)
+            .await
+            .map_err(|err| match err.downcast_ref::<MessageTooLarge>() {
+                // Large shares can still be browsed one directory at a time
+                Some(too_large) => anyhow::anyhow!(
+                    "The peer's file list of {} bytes is too large to fetch at once, \
+                     use the paged listing instead",
+                    too_large.len
+                ),
+                None => err,
+            })?;
 
-        let request = ProtocolRequestCommand::ListFileRequest { filter };
-        send_msg(&mut send, &request).await?;
+        match response {
+            ProtocolResponseCommand::ListFileResponse { files, .. } => Ok(files),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
 
-        let response: ProtocolResponseCommand = recv_msg(&mut recv).await?;
+    pub async fn list_remote_children(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+        parent: Option<String>,
+        cursor: u64,
+        limit: u32,
+    ) -> Result<ChildrenPage> {
+        let request_command = ProtocolRequestCommand::ListChildrenRequest {
+            parent,
+            cursor,
+            limit,
+        };
+        let response = request(sessions, node_addr.into(), &request_command).await?;
 
         match response {
-            ProtocolResponseCommand::ListFileResponse { files, .. } => Ok(files),
+            ProtocolResponseCommand::ListChildrenResponse {
+                children,
+                next_cursor,
+            } => Ok(ChildrenPage {
+                children,
+                next_cursor,
+            }),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
+
+    pub async fn search_remote(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+        query: String,
+        limit: u32,
+    ) -> Result<Vec<SearchMatch>> {
+        let request_command = ProtocolRequestCommand::SearchRequest { query, limit };
+        let response = request(sessions, node_addr.into(), &request_command).await?;
+
+        match response {
+            ProtocolResponseCommand::SearchResponse { matches } => Ok(matches),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
+
+    /// Sends a text message and waits for the peer to acknowledge it.
+    pub async fn send_chat_message(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+        message: ChatMessage,
+    ) -> Result<()> {
+        let id = message.id;
+        let request_command = ProtocolRequestCommand::ChatMessage { message };
+        let response = request(sessions, node_addr.into(), &request_command).await?;
+
+        match response {
+            ProtocolResponseCommand::ChatAck { id: acked } if acked == id => Ok(()),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
+
+    /// Asks the peer whether we hold one of its upload slots or where we are in line.
+    pub async fn slot_status(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+    ) -> Result<SlotStatus> {
+        let request_command = ProtocolRequestCommand::SlotStatusRequest;
+        let response = request(sessions, node_addr.into(), &request_command).await?;
+
+        match response {
+            ProtocolResponseCommand::SlotStatusResponse { status } => Ok(status),
             _ => Err(anyhow::anyhow!("Unexpected response type")),
         }
     }
+
+    /// Tells the peer where it stands with our upload slots.
+    pub async fn send_slot_status(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+        status: SlotStatus,
+    ) -> Result<()> {
+        let request_command = ProtocolRequestCommand::SlotStatusUpdate { status };
+        let response = request(sessions, node_addr.into(), &request_command).await?;
+
+        match response {
+            ProtocolResponseCommand::SlotStatusAck => Ok(()),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
+
+    /// Lists the files the peer shares with content `hash`, under any name.
+    pub async fn find_hash(
+        sessions: &Sessions,
+        node_addr: impl Into<NodeAddr>,
+        hash: Hash,
+    ) -> Result<Vec<SearchMatch>> {
+        let request_command = ProtocolRequestCommand::HashRequest { hash };
+        let response = request(sessions, node_addr.into(), &request_command).await?;
+
+        match response {
+            ProtocolResponseCommand::SearchResponse { matches } => Ok(matches),
+            _ => Err(anyhow::anyhow!("Unexpected response type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, size: u64) -> TreeNode {
+        TreeNode {
+            id: name.to_owned(),
+            name: name.to_owned(),
+            hash: String::new(),
+            path: name.to_owned(),
+            size: Some(size),
+            modified: None,
+            children: None,
+        }
+    }
+
+    fn dir(name: &str, children: Vec<TreeNode>) -> TreeNode {
+        TreeNode {
+            size: None,
+            children: Some(children),
+            ..file(name, 0)
+        }
+    }
+
+    fn tree() -> Vec<TreeNode> {
+        vec![
+            dir(
+                "music",
+                vec![
+                    file("a.mp3", 10),
+                    dir("live", vec![file("b.flac", 500)]),
+                    dir("covers", vec![file("c.jpg", 20)]),
+                ],
+            ),
+            file("readme.txt", 5),
+        ]
+    }
+
+    fn filter() -> FileFilter {
+        FileFilter {
+            name: None,
+            size_range: None,
+            is_dir: None,
+            depth: None,
+        }
+    }
+
+    fn names(nodes: &[TreeNode]) -> Vec<&str> {
+        nodes.iter().map(|node| node.name.as_str()).collect()
+    }
+
+    fn children(node: &TreeNode) -> &[TreeNode] {
+        node.children.as_deref().unwrap()
+    }
+
+    #[test]
+    fn filter_matches_names_as_globs_ignoring_case() {
+        let filter = FileFilter {
+            name: Some("*.MP3".to_owned()),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music"]);
+        assert_eq!(names(children(&nodes[0])), ["a.mp3"]);
+    }
+
+    #[test]
+    fn filter_keeps_directories_matching_by_name() {
+        let filter = FileFilter {
+            name: Some("LIV".to_owned()),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music"]);
+        let music = children(&nodes[0]);
+        assert_eq!(names(music), ["live"]);
+        assert!(children(&music[0]).is_empty());
+    }
+
+    #[test]
+    fn filter_limits_file_sizes() {
+        let filter = FileFilter {
+            size_range: Some((0, 15)),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music", "readme.txt"]);
+        assert_eq!(names(children(&nodes[0])), ["a.mp3"]);
+    }
+
+    #[test]
+    fn filter_by_type() {
+        let filter = FileFilter {
+            is_dir: Some(true),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music"]);
+        let music = children(&nodes[0]);
+        assert_eq!(names(music), ["live", "covers"]);
+        assert!(music.iter().all(|node| children(node).is_empty()));
+
+        let filter = FileFilter {
+            is_dir: Some(false),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music", "readme.txt"]);
+        assert_eq!(names(children(&nodes[0])), ["a.mp3", "live", "covers"]);
+    }
+
+    #[test]
+    fn filter_cuts_off_at_depth() {
+        let filter = FileFilter {
+            name: Some("*.flac".to_owned()),
+            depth: Some(0),
+            ..filter()
+        };
+        let nodes = filter.apply(tree());
+        assert_eq!(names(&nodes), ["music"]);
+        assert!(children(&nodes[0]).is_empty());
+    }
 }
@@ -0,0 +1,361 @@
+use anyhow::{Context, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+use crate::network::bandwidth::Bandwidth;
+use crate::network::protocol::TreeNode;
+use crate::network::transfer::{self, ProgressReporter};
+use crate::state::Peer;
+use iroh_blobs::net_protocol::Blobs;
+use iroh_blobs::store::fs::Store;
+
+/// Maximum number of downloads running at the same time.
+const MAX_CONCURRENT_TRANSFERS: usize = 3;
+/// How often queued transfers waiting for an offline peer are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const QUEUE_FILE: &str = "transfers.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferStatus {
+    Queued,
+    Running,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub id: u64,
+    pub node_id: NodeId,
+    pub node: TreeNode,
+    pub destination: PathBuf,
+    pub status: TransferStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TransferQueue {
+    next_id: u64,
+    transfers: Vec<Transfer>,
+    #[serde(skip)]
+    running: HashMap<u64, AbortHandle>,
+}
+
+/// Queues downloads, runs at most [`MAX_CONCURRENT_TRANSFERS`] of them at a time and
+/// persists the queue under `APP_DATA_DIR` so it survives a restart.
+///
+/// Pausing or cancelling aborts the running download. The partially downloaded blobs
+/// stay in the store, so resuming only fetches what is still missing.
+#[derive(Debug, Clone)]
+pub struct TransferManager {
+    queue: Arc<Mutex<TransferQueue>>,
+    wakeup: Arc<Notify>,
+    blobs: Blobs<Store>,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    bandwidth: Bandwidth,
+    app_handle: AppHandle,
+}
+
+impl TransferManager {
+    /// Loads the queue of the previous session and starts the scheduler.
+    pub fn spawn(
+        blobs: Blobs<Store>,
+        peers: Arc<Mutex<Vec<Peer>>>,
+        bandwidth: Bandwidth,
+        app: AppHandle,
+    ) -> Self {
+        let mut queue = load_queue().unwrap_or_else(|err| {
+            warn!("Could not load transfer queue, starting empty: {err:#}");
+            TransferQueue::default()
+        });
+        // Transfers interrupted by the shutdown continue where they left off
+        for transfer in &mut queue.transfers {
+            if transfer.status == TransferStatus::Running {
+                transfer.status = TransferStatus::Queued;
+            }
+        }
+
+        let this = Self {
+            queue: Arc::new(Mutex::new(queue)),
+            wakeup: Arc::new(Notify::new()),
+            blobs,
+            peers,
+            bandwidth,
+            app_handle: app,
+        };
+        let scheduler = this.clone();
+        tokio::spawn(async move {
+            loop {
+                scheduler.schedule().await;
+                let _ = tokio::time::timeout(RETRY_INTERVAL, scheduler.wakeup.notified()).await;
+            }
+        });
+        this
+    }
+
+    pub async fn enqueue(
+        &self,
+        node_id: NodeId,
+        mut node: TreeNode,
+        destination: PathBuf,
+    ) -> Result<Transfer> {
+        anyhow::ensure!(destination.is_absolute(), "Destination must be absolute");
+        // Only the node itself is needed to download it, not the listing below it
+        node.children = node.children.map(|_| Vec::new());
+
+        let mut queue = self.queue.lock().await;
+        let transfer = Transfer {
+            id: queue.next_id,
+            node_id,
+            node,
+            destination,
+            status: TransferStatus::Queued,
+            error: None,
+        };
+        queue.next_id += 1;
+        queue.transfers.push(transfer.clone());
+        save_queue(&queue).await?;
+        drop(queue);
+
+        self.emit_updated(&transfer);
+        self.wakeup.notify_one();
+        Ok(transfer)
+    }
+
+    pub async fn list(&self) -> Vec<Transfer> {
+        self.queue.lock().await.transfers.clone()
+    }
+
+    pub async fn pause(&self, id: u64) -> Result<()> {
+        self.update(id, |transfer| match transfer.status {
+            TransferStatus::Queued | TransferStatus::Running => {
+                transfer.status = TransferStatus::Paused;
+                Ok(())
+            }
+            status => Err(anyhow::anyhow!("Cannot pause a {status:?} transfer")),
+        })
+        .await
+    }
+
+    pub async fn resume(&self, id: u64) -> Result<()> {
+        self.update(id, |transfer| match transfer.status {
+            TransferStatus::Paused | TransferStatus::Failed => {
+                transfer.status = TransferStatus::Queued;
+                transfer.error = None;
+                Ok(())
+            }
+            status => Err(anyhow::anyhow!("Cannot resume a {status:?} transfer")),
+        })
+        .await
+    }
+
+    pub async fn cancel(&self, id: u64) -> Result<()> {
+        self.update(id, |transfer| match transfer.status {
+            TransferStatus::Completed | TransferStatus::Cancelled => Err(anyhow::anyhow!(
+                "Cannot cancel a {:?} transfer",
+                transfer.status
+            )),
+            _ => {
+                transfer.status = TransferStatus::Cancelled;
+                Ok(())
+            }
+        })
+        .await?;
+
+        let (node, others) = {
+            let queue = self.queue.lock().await;
+            let node = queue
+                .transfers
+                .iter()
+                .find(|t| t.id == id)
+                .map(|t| t.node.clone())
+                .context("Transfer not found")?;
+            // Transfers that may still run share blobs with this one if they download
+            // from the same share
+            let others: Vec<_> = queue
+                .transfers
+                .iter()
+                .filter(|t| {
+                    t.id != id
+                        && !matches!(
+                            t.status,
+                            TransferStatus::Completed | TransferStatus::Cancelled
+                        )
+                })
+                .map(|t| t.node.clone())
+                .collect();
+            (node, others)
+        };
+        let blobs_client = self.blobs.client();
+        let mut in_use = HashSet::new();
+        for other in &others {
+            in_use.extend(transfer::download_hashes(blobs_client, other).await?);
+        }
+        // Let the partially downloaded data nobody else needs be garbage collected
+        let hashes = transfer::download_hashes(blobs_client, &node).await?;
+        transfer::discard(
+            blobs_client,
+            hashes.into_iter().filter(|hash| !in_use.contains(hash)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Applies `change` to a transfer, stopping its download if it is no longer running.
+    async fn update(
+        &self,
+        id: u64,
+        change: impl FnOnce(&mut Transfer) -> Result<()>,
+    ) -> Result<()> {
+        let mut queue = self.queue.lock().await;
+        let transfer = queue
+            .transfers
+            .iter_mut()
+            .find(|t| t.id == id)
+            .context("Transfer not found")?;
+        change(transfer)?;
+        let transfer = transfer.clone();
+        if transfer.status != TransferStatus::Running {
+            if let Some(handle) = queue.running.remove(&id) {
+                handle.abort();
+            }
+        }
+        save_queue(&queue).await?;
+        drop(queue);
+
+        self.emit_updated(&transfer);
+        // A slot may have been freed or a transfer queued again
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Starts queued transfers whose peer is online until all slots are taken.
+    #[instrument(skip(self))]
+    async fn schedule(&self) {
+        let mut queue = self.queue.lock().await;
+        let peers = self.peers.lock().await;
+        let TransferQueue {
+            transfers, running, ..
+        } = &mut *queue;
+
+        let mut started = Vec::new();
+        for transfer in transfers
+            .iter_mut()
+            .filter(|t| t.status == TransferStatus::Queued)
+        {
+            if running.len() >= MAX_CONCURRENT_TRANSFERS {
+                break;
+            }
+            // Wait for the peer to be discovered again
+            let Some(peer) = peers
+                .iter()
+                .find(|p| p.node_addr.node_id == transfer.node_id)
+            else {
+                continue;
+            };
+            transfer.status = TransferStatus::Running;
+
+            let this = self.clone();
+            let node_addr = peer.node_addr.clone();
+            let job = transfer.clone();
+            let handle = tokio::spawn(async move {
+                let mut progress =
+                    ProgressReporter::new(this.app_handle.clone(), job.node.name.clone());
+                let result = transfer::download(
+                    &this.blobs,
+                    &this.bandwidth,
+                    node_addr,
+                    &job.node,
+                    &job.destination,
+                    &mut progress,
+                )
+                .await;
+                match &result {
+                    Ok(()) => progress.completed(),
+                    Err(err) => progress.failed(err),
+                }
+                this.finish(job.id, result).await;
+            });
+            running.insert(transfer.id, handle.abort_handle());
+            started.push(transfer.clone());
+        }
+        drop(peers);
+
+        if started.is_empty() {
+            return;
+        }
+        if let Err(err) = save_queue(&queue).await {
+            error!("Failed to save transfer queue: {err:#}");
+        }
+        drop(queue);
+        for transfer in &started {
+            info!(
+                "Starting transfer {} of {}",
+                transfer.id, transfer.node.name
+            );
+            self.emit_updated(transfer);
+        }
+    }
+
+    async fn finish(&self, id: u64, result: Result<()>) {
+        let mut queue = self.queue.lock().await;
+        queue.running.remove(&id);
+        let Some(transfer) = queue.transfers.iter_mut().find(|t| t.id == id) else {
+            return;
+        };
+        if transfer.status != TransferStatus::Running {
+            return;
+        }
+        match result {
+            Ok(()) => transfer.status = TransferStatus::Completed,
+            Err(err) => {
+                transfer.status = TransferStatus::Failed;
+                transfer.error = Some(format!("{err:#}"));
+            }
+        }
+        let transfer = transfer.clone();
+        if let Err(err) = save_queue(&queue).await {
+            error!("Failed to save transfer queue: {err:#}");
+        }
+        drop(queue);
+
+        self.emit_updated(&transfer);
+        self.wakeup.notify_one();
+    }
+
+    fn emit_updated(&self, transfer: &Transfer) {
+        let _ = self.app_handle.emit("transfer::updated", transfer);
+    }
+}
+
+fn load_queue() -> Result<TransferQueue> {
+    let path = crate::global::APP_DATA_DIR.join(QUEUE_FILE);
+    if !path.exists() {
+        return Ok(TransferQueue::default());
+    }
+    let data = std::fs::read(&path)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+async fn save_queue(queue: &TransferQueue) -> Result<()> {
+    let path = crate::global::APP_DATA_DIR.join(QUEUE_FILE);
+    let data = serde_json::to_vec_pretty(queue)?;
+    // Write to a temporary file first so a crash never leaves a truncated queue
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .context("Failed to save transfer queue")?;
+    Ok(())
+}
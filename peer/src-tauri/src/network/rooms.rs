@@ -0,0 +1,624 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::Signature;
+use futures_lite::StreamExt;
+use iroh::{Endpoint, NodeId, SecretKey};
+use iroh_docs::engine::LiveEvent;
+use iroh_docs::rpc::client::docs::{Entry, ShareMode};
+use iroh_docs::rpc::AddrInfoOptions;
+use iroh_docs::store::Query;
+use iroh_docs::{AuthorId, ContentStatus, DocTicket, NamespaceId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::network::chat::MAX_MESSAGE_LEN;
+use crate::network::host::{self, Verification, HOST_ADDR};
+use crate::network::protocol::BlobsClient;
+use crate::state::Peer;
+
+type DocsConnector = quic_rpc::transport::flume::FlumeConnector<
+    iroh_docs::rpc::proto::Response,
+    iroh_docs::rpc::proto::Request,
+>;
+pub type DocsClient = iroh_docs::rpc::client::docs::Client<DocsConnector>;
+type Doc = iroh_docs::rpc::client::docs::Doc<DocsConnector>;
+
+const NAME_KEY: &[u8] = b"meta/name";
+const MESSAGE_PREFIX: &str = "messages/";
+/// Prefixes the author id a node signs, so the signature means nothing elsewhere.
+const AUTHOR_LINK_CONTEXT: &[u8] = b"hermes/room-author/";
+
+/// Value stored under a `messages/` key of a room document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MessageRecord {
+    username: String,
+    text: String,
+    sent_at: DateTime<Utc>,
+    /// Missing in messages written before authors were linked to nodes.
+    #[serde(default)]
+    link: Option<AuthorLink>,
+}
+
+/// Links the document author of a message to the node that wrote it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuthorLink {
+    node_id: NodeId,
+    /// The author id signed with the node's key.
+    signature: Signature,
+}
+
+impl AuthorLink {
+    fn new(secret_key: &SecretKey, author: AuthorId) -> Self {
+        Self {
+            node_id: secret_key.public(),
+            signature: secret_key.sign(&link_message(author)),
+        }
+    }
+
+    /// The linked node, if it signed `author`.
+    fn verify(&self, author: AuthorId) -> Option<NodeId> {
+        self.node_id
+            .verify(&link_message(author), &self.signature)
+            .ok()
+            .map(|()| self.node_id)
+    }
+}
+
+fn link_message(author: AuthorId) -> Vec<u8> {
+    [AUTHOR_LINK_CONTEXT, author.as_bytes()].concat()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomMessage {
+    pub author: AuthorId,
+    /// The node that wrote the message, `None` if the author is not linked to one.
+    pub node_id: Option<NodeId>,
+    /// The name the author claims, or their short node id when it cannot be
+    /// confirmed, see [`Names`].
+    pub username: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInfo {
+    pub id: NamespaceId,
+    pub name: String,
+}
+
+/// Payload of `room::message`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomMessageEvent {
+    pub room_id: NamespaceId,
+    pub message: RoomMessage,
+}
+
+struct Room {
+    doc: Doc,
+    /// Forwards new messages of the room to the frontend.
+    events_task: JoinHandle<()>,
+    /// Content hashes of the room's entries, which members may fetch from us.
+    contents: Arc<std::sync::Mutex<HashSet<iroh_blobs::Hash>>>,
+}
+
+/// Group chat rooms, each one an iroh-docs document replicated among its members.
+///
+/// Every message is an entry under `messages/<sent_at>/<author>`, so members that join
+/// late receive the history when their replica syncs, and messages sort by timestamp
+/// and then author. Messages carry an [`AuthorLink`] from our author to our node.
+#[derive(Clone)]
+pub struct RoomManager {
+    docs_client: DocsClient,
+    blobs_client: BlobsClient,
+    author: AuthorId,
+    link: AuthorLink,
+    names: Names,
+    rooms: Arc<Mutex<HashMap<NamespaceId, Room>>>,
+    app_handle: AppHandle,
+}
+
+impl std::fmt::Debug for RoomManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomManager")
+            .field("author", &self.author)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RoomManager {
+    /// Opens the rooms joined in previous sessions.
+    pub async fn spawn(
+        docs_client: DocsClient,
+        blobs_client: BlobsClient,
+        endpoint: Endpoint,
+        peers: Arc<Mutex<Vec<Peer>>>,
+        app: AppHandle,
+    ) -> Result<Self> {
+        let author = docs_client.authors().default().await?;
+        // Members tell which node wrote a message from the link
+        let link = AuthorLink::new(endpoint.secret_key(), author);
+        let manager = Self {
+            docs_client,
+            blobs_client,
+            author,
+            link,
+            names: Names {
+                endpoint,
+                peers,
+                verified: Default::default(),
+            },
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            app_handle: app,
+        };
+
+        for doc in reopen_docs(&manager.docs_client).await? {
+            manager.open_room(doc).await?;
+        }
+        Ok(manager)
+    }
+
+    pub async fn create(&self, name: &str) -> Result<RoomInfo> {
+        let doc = self.docs_client.create().await?;
+        doc.set_bytes(self.author, NAME_KEY.to_vec(), name.as_bytes().to_vec())
+            .await?;
+        let info = RoomInfo {
+            id: doc.id(),
+            name: name.to_owned(),
+        };
+        self.open_room(doc).await?;
+        info!("Created room {}", info.id);
+        Ok(info)
+    }
+
+    /// Joins the room of `ticket`, its history syncs in the background.
+    pub async fn join(&self, ticket: &str) -> Result<RoomInfo> {
+        let ticket = DocTicket::from_str(ticket.trim()).context("Invalid room ticket")?;
+        let doc = self.docs_client.import(ticket).await?;
+        let id = doc.id();
+        self.open_room(doc).await?;
+        info!("Joined room {id}");
+        self.info(id).await
+    }
+
+    /// A ticket others can join the room with, granting write access.
+    pub async fn ticket(&self, id: NamespaceId) -> Result<DocTicket> {
+        let doc = self.doc(id).await?;
+        doc.share(ShareMode::Write, AddrInfoOptions::RelayAndAddresses)
+            .await
+    }
+
+    pub async fn leave(&self, id: NamespaceId) -> Result<()> {
+        let room = self
+            .rooms
+            .lock()
+            .await
+            .remove(&id)
+            .context("Unknown room")?;
+        room.events_task.abort();
+        room.doc.leave().await?;
+        self.docs_client.drop_doc(id).await?;
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Result<Vec<RoomInfo>> {
+        let ids: Vec<NamespaceId> = self.rooms.lock().await.keys().copied().collect();
+        let mut infos = Vec::with_capacity(ids.len());
+        for id in ids {
+            infos.push(self.info(id).await?);
+        }
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(infos)
+    }
+
+    /// All messages of a room ordered by timestamp, then author.
+    pub async fn messages(&self, id: NamespaceId) -> Result<Vec<RoomMessage>> {
+        let doc = self.doc(id).await?;
+        let mut entries = doc.get_many(Query::key_prefix(MESSAGE_PREFIX)).await?;
+        let mut messages = Vec::new();
+        while let Some(entry) = entries.next().await {
+            // Content that has not synced yet arrives later as a `room::message` event
+            if let Some(mut message) = read_message(&self.blobs_client, &entry?).await {
+                self.names.check(&mut message).await;
+                messages.push(message);
+            }
+        }
+        messages.sort_by(|a, b| (a.sent_at, a.author).cmp(&(b.sent_at, b.author)));
+        Ok(messages)
+    }
+
+    pub async fn send(&self, id: NamespaceId, username: &str, text: &str) -> Result<RoomMessage> {
+        anyhow::ensure!(
+            text.len() <= MAX_MESSAGE_LEN,
+            "Message exceeds {MAX_MESSAGE_LEN} bytes"
+        );
+        let doc = self.doc(id).await?;
+        let record = MessageRecord {
+            username: username.to_owned(),
+            text: text.to_owned(),
+            sent_at: Utc::now(),
+            link: Some(self.link.clone()),
+        };
+        let key = format!(
+            "{MESSAGE_PREFIX}{:020}/{}",
+            record.sent_at.timestamp_micros(),
+            self.author
+        );
+        doc.set_bytes(self.author, key.into_bytes(), serde_json::to_vec(&record)?)
+            .await?;
+        Ok(RoomMessage {
+            author: self.author,
+            node_id: Some(self.link.node_id),
+            username: record.username,
+            text: record.text,
+            sent_at: record.sent_at,
+        })
+    }
+
+    /// Whether `hash` is the content of an entry in one of our rooms.
+    pub async fn holds(&self, hash: &iroh_blobs::Hash) -> bool {
+        let rooms = self.rooms.lock().await;
+        rooms
+            .values()
+            .any(|room| room.contents.lock().expect("poisoned").contains(hash))
+    }
+
+    async fn doc(&self, id: NamespaceId) -> Result<Doc> {
+        let rooms = self.rooms.lock().await;
+        Ok(rooms.get(&id).context("Unknown room")?.doc.clone())
+    }
+
+    async fn info(&self, id: NamespaceId) -> Result<RoomInfo> {
+        let doc = self.doc(id).await?;
+        // Set by whoever created the room, which is not us for rooms joined by ticket
+        let mut entries = doc.get_many(Query::key_exact(NAME_KEY)).await?;
+        let name = match entries.next().await {
+            Some(entry) => self
+                .blobs_client
+                .read_to_bytes(entry?.content_hash())
+                .await
+                .ok(),
+            None => None,
+        };
+        Ok(RoomInfo {
+            id,
+            name: name
+                .map(|name| String::from_utf8_lossy(&name).into_owned())
+                .unwrap_or_else(|| id.fmt_short()),
+        })
+    }
+
+    async fn open_room(&self, doc: Doc) -> Result<()> {
+        let id = doc.id();
+        let events = doc.subscribe().await?;
+        let mut contents = HashSet::new();
+        let mut entries = doc.get_many(Query::all()).await?;
+        while let Some(entry) = entries.next().await {
+            contents.insert(entry?.content_hash());
+        }
+        let contents = Arc::new(std::sync::Mutex::new(contents));
+        let events_task = tokio::spawn(forward_messages(
+            id,
+            events,
+            Arc::clone(&contents),
+            self.blobs_client.clone(),
+            self.names.clone(),
+            self.app_handle.clone(),
+        ));
+        let room = Room {
+            doc,
+            events_task,
+            contents,
+        };
+        let previous = self.rooms.lock().await.insert(id, room);
+        if let Some(previous) = previous {
+            previous.events_task.abort();
+        }
+        Ok(())
+    }
+}
+
+/// Opens the rooms joined in previous sessions and syncs them again. Without peers
+/// given, a room syncs with the members the store remembers from earlier syncs.
+async fn reopen_docs(docs_client: &DocsClient) -> Result<Vec<Doc>> {
+    let mut ids = Vec::new();
+    let mut list = docs_client.list().await?;
+    while let Some(item) = list.next().await {
+        let (id, _capability) = item?;
+        ids.push(id);
+    }
+    let mut docs = Vec::with_capacity(ids.len());
+    for id in ids {
+        match docs_client.open(id).await? {
+            Some(doc) => {
+                doc.start_sync(Vec::new()).await?;
+                docs.push(doc);
+            }
+            None => warn!("Room {id} disappeared from the store"),
+        }
+    }
+    Ok(docs)
+}
+
+/// Checks the usernames room messages claim against the nodes that wrote them.
+///
+/// A name is confirmed when the host has it registered for the node, or, without a
+/// host, when the node advertises it on the local network.
+#[derive(Clone)]
+struct Names {
+    endpoint: Endpoint,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    /// Answers of the host by node and claimed name.
+    verified: Arc<Mutex<HashMap<(NodeId, String), bool>>>,
+}
+
+impl Names {
+    /// Replaces the username of `message` with the node id of its writer unless
+    /// confirmed, or with the author id if the author is not linked to a node.
+    async fn check(&self, message: &mut RoomMessage) {
+        let confirmed = match message.node_id {
+            Some(node_id) => self.confirmed(node_id, &message.username).await,
+            None => false,
+        };
+        if !confirmed {
+            message.username = match message.node_id {
+                Some(node_id) => node_id.fmt_short(),
+                None => message.author.fmt_short(),
+            };
+        }
+    }
+
+    async fn confirmed(&self, node_id: NodeId, username: &str) -> bool {
+        if node_id == self.endpoint.node_id() {
+            return true;
+        }
+        if HOST_ADDR.is_none() {
+            return self
+                .peers
+                .lock()
+                .await
+                .iter()
+                .any(|peer| peer.node_addr.node_id == node_id && peer.username == username);
+        }
+        let key = (node_id, username.to_owned());
+        if let Some(&verified) = self.verified.lock().await.get(&key) {
+            return verified;
+        }
+        match host::verify(&self.endpoint, node_id, username).await {
+            Ok(verification) => {
+                let verified = verification == Verification::Verified;
+                self.verified.lock().await.insert(key, verified);
+                verified
+            }
+            Err(err) => {
+                warn!("Could not verify room member {node_id}: {err:#}");
+                false
+            }
+        }
+    }
+}
+
+/// Emits `room::message` for every message added to the room, once its content is
+/// available locally, and records the content hashes of new entries in `contents`.
+async fn forward_messages(
+    room_id: NamespaceId,
+    mut events: impl futures_lite::Stream<Item = Result<LiveEvent>> + Unpin,
+    contents: Arc<std::sync::Mutex<HashSet<iroh_blobs::Hash>>>,
+    blobs_client: BlobsClient,
+    names: Names,
+    app: AppHandle,
+) {
+    // Remote entries whose content is still being downloaded
+    let mut pending: HashMap<iroh_blobs::Hash, Entry> = HashMap::new();
+    let mut emitted: HashSet<iroh_blobs::Hash> = HashSet::new();
+    while let Some(event) = events.next().await {
+        let entry = match event {
+            Ok(LiveEvent::InsertLocal { entry }) => {
+                contents
+                    .lock()
+                    .expect("poisoned")
+                    .insert(entry.content_hash());
+                entry
+            }
+            Ok(LiveEvent::InsertRemote {
+                entry,
+                content_status,
+                ..
+            }) => {
+                contents
+                    .lock()
+                    .expect("poisoned")
+                    .insert(entry.content_hash());
+                if content_status != ContentStatus::Complete {
+                    pending.insert(entry.content_hash(), entry);
+                    continue;
+                }
+                entry
+            }
+            Ok(LiveEvent::ContentReady { hash }) => match pending.remove(&hash) {
+                Some(entry) => entry,
+                None => continue,
+            },
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("Room {room_id} event stream failed: {err:#}");
+                break;
+            }
+        };
+        if !emitted.insert(entry.content_hash()) {
+            continue;
+        }
+        if let Some(mut message) = read_message(&blobs_client, &entry).await {
+            names.check(&mut message).await;
+            let payload = RoomMessageEvent { room_id, message };
+            let _ = app.emit("room::message", payload);
+        }
+    }
+}
+
+async fn read_message(blobs_client: &BlobsClient, entry: &Entry) -> Option<RoomMessage> {
+    if !entry.key().starts_with(MESSAGE_PREFIX.as_bytes()) {
+        return None;
+    }
+    let content = blobs_client
+        .read_to_bytes(entry.content_hash())
+        .await
+        .ok()?;
+    let record: MessageRecord = match serde_json::from_slice(&content) {
+        Ok(record) => record,
+        Err(err) => {
+            warn!("Ignoring malformed room message: {err}");
+            return None;
+        }
+    };
+    Some(RoomMessage {
+        author: entry.author(),
+        node_id: record.link.and_then(|link| link.verify(entry.author())),
+        username: record.username,
+        text: record.text,
+        sent_at: record.sent_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::protocol::Router;
+    use iroh::{RelayMode, SecretKey};
+    use iroh_blobs::net_protocol::Blobs;
+    use iroh_docs::protocol::Docs;
+    use iroh_gossip::net::Gossip;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn author_links_verify_only_for_the_signed_author() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let author = iroh_docs::Author::new(&mut rand::rngs::OsRng).id();
+        let other = iroh_docs::Author::new(&mut rand::rngs::OsRng).id();
+        let link = AuthorLink::new(&secret_key, author);
+        assert_eq!(link.verify(author), Some(secret_key.public()));
+        assert_eq!(link.verify(other), None);
+
+        // Claiming another node's link does not work either
+        let forged = AuthorLink {
+            node_id: SecretKey::generate(rand::rngs::OsRng).public(),
+            ..link
+        };
+        assert_eq!(forged.verify(author), None);
+    }
+
+    #[test]
+    fn author_links_survive_the_message_record() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let author = iroh_docs::Author::new(&mut rand::rngs::OsRng).id();
+        let record = MessageRecord {
+            username: "alice".to_owned(),
+            text: "hi".to_owned(),
+            sent_at: Utc::now(),
+            link: Some(AuthorLink::new(&secret_key, author)),
+        };
+        let record: MessageRecord =
+            serde_json::from_slice(&serde_json::to_vec(&record).unwrap()).unwrap();
+        let link = record.link.unwrap();
+        assert_eq!(link.verify(author), Some(secret_key.public()));
+
+        let old = r#"{"username":"bob","text":"hi","sent_at":"2025-01-01T00:00:00Z"}"#;
+        let old: MessageRecord = serde_json::from_str(old).unwrap();
+        assert!(old.link.is_none());
+    }
+
+    const SYNC_TIMEOUT: Duration = Duration::from_secs(20);
+
+    /// A node with persistent docs, like the app's, on the loopback interface only.
+    struct Node {
+        router: Router,
+        docs_client: DocsClient,
+    }
+
+    impl Node {
+        async fn spawn(dir: &Path, secret_key: SecretKey) -> Result<Self> {
+            let endpoint = Endpoint::builder()
+                .secret_key(secret_key)
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+                .await?;
+            let blobs = Blobs::persistent(dir.join("blobs")).await?.build(&endpoint);
+            let gossip = Gossip::builder().spawn(endpoint.clone()).await?;
+            let docs = Docs::persistent(dir.join("docs"))
+                .spawn(&blobs, &gossip)
+                .await?;
+            let docs_client = docs.client().clone();
+            let router = Router::builder(endpoint)
+                .accept(iroh_blobs::ALPN, blobs)
+                .accept(iroh_gossip::ALPN, gossip)
+                .accept(iroh_docs::ALPN, docs)
+                .spawn();
+            Ok(Self {
+                router,
+                docs_client,
+            })
+        }
+    }
+
+    async fn wait_for_entry(doc: &Doc, author: AuthorId, key: &[u8]) -> Result<()> {
+        tokio::time::timeout(SYNC_TIMEOUT, async {
+            loop {
+                if doc.get_exact(author, key.to_vec(), false).await?.is_some() {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("Entry did not sync in time")?
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reopened_rooms_keep_syncing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let alice = Node::spawn(
+            &dir.path().join("alice"),
+            SecretKey::generate(rand::rngs::OsRng),
+        )
+        .await?;
+        let alice_addr = alice.router.endpoint().node_addr().await?;
+        let alice_author = alice.docs_client.authors().default().await?;
+        let alice_doc = alice.docs_client.create().await?;
+        alice_doc
+            .set_bytes(alice_author, b"first".to_vec(), b"1".to_vec())
+            .await?;
+        let ticket = alice_doc
+            .share(ShareMode::Write, AddrInfoOptions::Addresses)
+            .await?;
+
+        let bob_key = SecretKey::generate(rand::rngs::OsRng);
+        let bob_dir = dir.path().join("bob");
+        let bob = Node::spawn(&bob_dir, bob_key.clone()).await?;
+        let bob_doc = bob.docs_client.import(ticket).await?;
+        wait_for_entry(&bob_doc, alice_author, b"first").await?;
+
+        // Restart bob from the same storage
+        bob.router.shutdown().await?;
+        drop(bob_doc);
+        drop(bob);
+        let bob = Node::spawn(&bob_dir, bob_key).await?;
+        bob.router.endpoint().add_node_addr(alice_addr)?;
+        let docs = reopen_docs(&bob.docs_client).await?;
+        assert_eq!(docs.len(), 1);
+
+        alice_doc
+            .set_bytes(alice_author, b"second".to_vec(), b"2".to_vec())
+            .await?;
+        wait_for_entry(&docs[0], alice_author, b"second").await?;
+
+        bob.router.shutdown().await?;
+        alice.router.shutdown().await?;
+        Ok(())
+    }
+}
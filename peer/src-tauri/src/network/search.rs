@@ -0,0 +1,160 @@
+use anyhow::Result;
+use iroh_blobs::Hash;
+use serde::Serialize;
+use std::collections::HashMap;
+use tauri::{AppHandle, Emitter};
+use tokio::task::JoinSet;
+use tokio::time::Duration;
+use tracing::{instrument, warn};
+
+use crate::network::protocol::client::{find_hash, search_remote};
+use crate::network::protocol::{SearchMatch, UnsupportedRequest};
+use crate::network::sessions::Sessions;
+use crate::state::{Peer, PeerSerializable};
+
+/// How long to wait for a single peer to answer a search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of matches asked from each peer.
+const RESULTS_PER_PEER: u32 = 100;
+
+/// Payload of the `search::result` event, one per match.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub query: String,
+    pub peer: PeerSerializable,
+    #[serde(flatten)]
+    pub file: SearchMatch,
+}
+
+/// All matches sharing the same content hash.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchGroup {
+    pub hash: String,
+    pub name: String,
+    pub size: Option<u64>,
+    pub peers: Vec<PeerSerializable>,
+    pub paths: Vec<String>,
+}
+
+/// Sends `query` to all `peers` concurrently, emitting a `search::result` event for every
+/// match as soon as a peer answers. Peers that fail or do not answer within
+/// [`SEARCH_TIMEOUT`] are skipped.
+///
+/// Returns the matches grouped by content hash, most widely shared first.
+#[instrument(skip(sessions, peers, app))]
+pub async fn search_peers(
+    sessions: &Sessions,
+    peers: Vec<Peer>,
+    query: String,
+    app: AppHandle,
+) -> Vec<SearchGroup> {
+    let mut tasks = fan_out(sessions, peers, &query);
+    let mut groups: Vec<SearchGroup> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    while let Some((peer, matches)) = next_answer(&mut tasks).await {
+        let peer: PeerSerializable = peer.into();
+        for file in matches {
+            let _ = app.emit(
+                "search::result",
+                SearchResult {
+                    query: query.clone(),
+                    peer: peer.clone(),
+                    file: file.clone(),
+                },
+            );
+
+            let index = *group_index.entry(file.hash.clone()).or_insert_with(|| {
+                groups.push(SearchGroup {
+                    hash: file.hash.clone(),
+                    name: file.name.clone(),
+                    size: file.size,
+                    peers: Vec::new(),
+                    paths: Vec::new(),
+                });
+                groups.len() - 1
+            });
+            let group = &mut groups[index];
+            if !group.peers.iter().any(|p| p.node_id == peer.node_id) {
+                group.peers.push(peer.clone());
+            }
+            group.paths.push(file.path);
+        }
+    }
+
+    groups.sort_by(|a, b| b.peers.len().cmp(&a.peers.len()));
+    groups
+}
+
+/// Finds the peers sharing a file with content `hash`, under any name.
+///
+/// Peers too old to look up hashes are searched for `name` instead.
+#[instrument(skip(sessions, peers))]
+pub async fn find_providers(
+    sessions: &Sessions,
+    peers: Vec<Peer>,
+    name: &str,
+    hash: Hash,
+) -> Vec<Peer> {
+    let mut tasks = JoinSet::new();
+    for peer in peers {
+        let sessions = sessions.clone();
+        let name = name.to_owned();
+        tasks.spawn(async move {
+            let lookup = async {
+                match find_hash(&sessions, peer.node_addr.clone(), hash).await {
+                    Err(err) if err.is::<UnsupportedRequest>() => {
+                        search_remote(&sessions, peer.node_addr.clone(), name, RESULTS_PER_PEER)
+                            .await
+                    }
+                    result => result,
+                }
+            };
+            let result = tokio::time::timeout(SEARCH_TIMEOUT, lookup).await;
+            (peer, result)
+        });
+    }
+
+    let hash = hash.to_string();
+    let mut providers = Vec::new();
+    while let Some((peer, matches)) = next_answer(&mut tasks).await {
+        if matches.iter().any(|file| file.hash == hash) {
+            providers.push(peer);
+        }
+    }
+    providers
+}
+
+type SearchTasks = JoinSet<(Peer, Result<Result<Vec<SearchMatch>>, tokio::time::error::Elapsed>)>;
+
+/// Sends `query` to every peer concurrently, each bounded by [`SEARCH_TIMEOUT`].
+fn fan_out(sessions: &Sessions, peers: Vec<Peer>, query: &str) -> SearchTasks {
+    let mut tasks = JoinSet::new();
+    for peer in peers {
+        let sessions = sessions.clone();
+        let query = query.to_owned();
+        tasks.spawn(async move {
+            let result = tokio::time::timeout(
+                SEARCH_TIMEOUT,
+                search_remote(&sessions, peer.node_addr.clone(), query, RESULTS_PER_PEER),
+            )
+            .await;
+            (peer, result)
+        });
+    }
+    tasks
+}
+
+/// Waits for the next peer to answer, skipping peers that failed or timed out.
+async fn next_answer(tasks: &mut SearchTasks) -> Option<(Peer, Vec<SearchMatch>)> {
+    while let Some(joined) = tasks.join_next().await {
+        let Ok((peer, result)) = joined else {
+            continue;
+        };
+        match result {
+            Ok(Ok(matches)) => return Some((peer, matches)),
+            Ok(Err(err)) => warn!("Search on {} failed: {err:#}", peer.username),
+            Err(_) => warn!("Search on {} timed out", peer.username),
+        }
+    }
+    None
+}
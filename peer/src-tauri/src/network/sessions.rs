@@ -0,0 +1,322 @@
+use anyhow::Result;
+use iroh::endpoint::{Connection, Endpoint, RecvStream, SendStream};
+use iroh::{NodeAddr, NodeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tauri::{AppHandle, EventId, Listener};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+use crate::network::protocol::{negotiate_version, ConnectionRole, ALPN, SESSION_PROTOCOL_VERSION};
+use crate::state::PeerSerializable;
+
+/// Sessions without an open request for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Application error code sessions are closed with when they are no longer needed.
+const SESSION_CLOSED_CODE: u32 = 0;
+
+/// One negotiated connection to every peer we talk the Hermes protocol with.
+///
+/// Requests go out on a new bi-stream of the peer's connection, so only the first
+/// one pays for connecting and negotiating the protocol version. Sessions are closed
+/// once no request was open on them for [`IDLE_TIMEOUT`], and dropped when their
+/// connection is lost or the peer reappears with `peer::back`, so the next request
+/// connects again.
+///
+/// Peers older than [`SESSION_PROTOCOL_VERSION`] only serve one stream per connection
+/// and get a new connection for every request, like before.
+///
+/// [`Sessions::shutdown`] stops the background work and closes all sessions.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    endpoint: Endpoint,
+    sessions: Arc<Mutex<HashMap<NodeId, Session>>>,
+    /// Taken by [`Sessions::shutdown`].
+    background: Arc<std::sync::Mutex<Option<Background>>>,
+}
+
+/// The event listeners and the task that close sessions in the background.
+#[derive(Debug)]
+struct Background {
+    app: AppHandle,
+    listeners: Vec<EventId>,
+    maintenance: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Session {
+    connection: Connection,
+    version: u16,
+    activity: Arc<std::sync::Mutex<Activity>>,
+}
+
+/// The requests open on a session, updated by their [`RequestStream`]s.
+#[derive(Debug)]
+struct Activity {
+    open_streams: usize,
+    /// When the last request was opened or finished.
+    last_used: Instant,
+}
+
+impl Activity {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.open_streams == 0 && now.duration_since(self.last_used) >= IDLE_TIMEOUT
+    }
+}
+
+/// Counts a request stream as open on its session until dropped.
+#[derive(Debug)]
+struct StreamGuard(Arc<std::sync::Mutex<Activity>>);
+
+impl StreamGuard {
+    fn new(activity: &Arc<std::sync::Mutex<Activity>>) -> Self {
+        let mut guard = activity.lock().expect("poisoned");
+        guard.open_streams += 1;
+        guard.last_used = Instant::now();
+        Self(Arc::clone(activity))
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut activity = self.0.lock().expect("poisoned");
+        activity.open_streams -= 1;
+        activity.last_used = Instant::now();
+    }
+}
+
+/// A stream for a single request.
+#[derive(Debug)]
+pub struct RequestStream {
+    pub send: SendStream,
+    pub recv: RecvStream,
+    /// Protocol version negotiated with the peer.
+    pub version: u16,
+    /// Keeps connections that are not pooled open until the request is done.
+    _connection: Connection,
+    /// Keeps a pooled session from being closed as idle while the request runs.
+    _guard: Option<StreamGuard>,
+}
+
+impl Sessions {
+    pub fn spawn(endpoint: Endpoint, app: &AppHandle) -> Self {
+        let sessions = Self {
+            endpoint,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            background: Default::default(),
+        };
+
+        // A peer that comes back may have new addresses, or its old connection may be
+        // stuck until QUIC times it out
+        let (reset_tx, mut reset_rx) = mpsc::unbounded_channel::<NodeId>();
+        let listeners = ["peer::back", "peer::left"]
+            .into_iter()
+            .map(|event_name| {
+                let reset_tx = reset_tx.clone();
+                app.listen(event_name, move |event| {
+                    if let Ok(peer) = serde_json::from_str::<PeerSerializable>(event.payload()) {
+                        let _ = reset_tx.send(peer.node_id);
+                    }
+                })
+            })
+            .collect();
+        let this = sessions.clone();
+        let maintenance = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_TIMEOUT / 2);
+            loop {
+                tokio::select! {
+                    Some(node_id) = reset_rx.recv() => this.close(node_id).await,
+                    _ = interval.tick() => this.close_idle().await,
+                }
+            }
+        });
+        *sessions.background.lock().expect("poisoned") = Some(Background {
+            app: app.clone(),
+            listeners,
+            maintenance,
+        });
+        sessions
+    }
+
+    /// Stops listening for peers and closing idle sessions, then closes all sessions.
+    pub async fn shutdown(&self) {
+        let background = self.background.lock().expect("poisoned").take();
+        if let Some(background) = background {
+            for listener in background.listeners {
+                background.app.unlisten(listener);
+            }
+            background.maintenance.abort();
+        }
+        for (node_id, session) in self.sessions.lock().await.drain() {
+            trace!("Closing session with {node_id}");
+            session
+                .connection
+                .close(SESSION_CLOSED_CODE.into(), b"shutting down");
+        }
+    }
+
+    /// Opens a stream to `node_addr` for one request, on the open session with the
+    /// peer if there is one.
+    pub async fn open(&self, node_addr: NodeAddr) -> Result<RequestStream> {
+        let node_id = node_addr.node_id;
+        let session = {
+            let sessions = self.sessions.lock().await;
+            sessions.get(&node_id).map(|session| {
+                (
+                    session.connection.clone(),
+                    session.version,
+                    StreamGuard::new(&session.activity),
+                )
+            })
+        };
+        if let Some((connection, version, guard)) = session {
+            match connection.open_bi().await {
+                Ok((send, recv)) => {
+                    return Ok(RequestStream {
+                        send,
+                        recv,
+                        version,
+                        _connection: connection,
+                        _guard: Some(guard),
+                    })
+                }
+                Err(err) => {
+                    debug!("Session with {node_id} is gone, reconnecting: {err}");
+                    self.remove(node_id, &connection).await;
+                }
+            }
+        }
+
+        let connection = self.endpoint.connect(node_addr, ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        let version = negotiate_version(&mut send, &mut recv, ConnectionRole::Initiator).await?;
+        let guard = if version >= SESSION_PROTOCOL_VERSION {
+            self.insert(node_id, connection.clone(), version).await
+        } else {
+            None
+        };
+        Ok(RequestStream {
+            send,
+            recv,
+            version,
+            _connection: connection,
+            _guard: guard,
+        })
+    }
+
+    /// Keeps `connection` as the session with `node_id`, returns the guard for the
+    /// request that opened it.
+    async fn insert(
+        &self,
+        node_id: NodeId,
+        connection: Connection,
+        version: u16,
+    ) -> Option<StreamGuard> {
+        let mut sessions = self.sessions.lock().await;
+        // Another request may have connected at the same time, keep its session
+        if sessions.contains_key(&node_id) {
+            return None;
+        }
+        trace!("Opened session with {node_id}");
+        let activity = Arc::new(std::sync::Mutex::new(Activity {
+            open_streams: 0,
+            last_used: Instant::now(),
+        }));
+        let guard = StreamGuard::new(&activity);
+        sessions.insert(
+            node_id,
+            Session {
+                connection: connection.clone(),
+                version,
+                activity,
+            },
+        );
+        drop(sessions);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            connection.closed().await;
+            this.remove(node_id, &connection).await;
+        });
+        Some(guard)
+    }
+
+    /// Forgets the session with `node_id` if it still uses `connection`.
+    async fn remove(&self, node_id: NodeId, connection: &Connection) {
+        let mut sessions = self.sessions.lock().await;
+        if sessions
+            .get(&node_id)
+            .is_some_and(|session| session.connection.stable_id() == connection.stable_id())
+        {
+            sessions.remove(&node_id);
+            trace!("Session with {node_id} ended");
+        }
+    }
+
+    /// Closes the session with `node_id`, requests still running on it fail.
+    pub async fn close(&self, node_id: NodeId) {
+        if let Some(session) = self.sessions.lock().await.remove(&node_id) {
+            session
+                .connection
+                .close(SESSION_CLOSED_CODE.into(), b"session closed");
+        }
+    }
+
+    /// Closes the sessions without open requests that have been idle for
+    /// [`IDLE_TIMEOUT`].
+    async fn close_idle(&self) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|node_id, session| {
+            let idle = session.activity.lock().expect("poisoned").is_idle(now);
+            if idle {
+                trace!("Closing idle session with {node_id}");
+                session
+                    .connection
+                    .close(SESSION_CLOSED_CODE.into(), b"idle");
+            }
+            !idle
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity() -> Arc<std::sync::Mutex<Activity>> {
+        Arc::new(std::sync::Mutex::new(Activity {
+            open_streams: 0,
+            last_used: Instant::now(),
+        }))
+    }
+
+    #[test]
+    fn sessions_with_open_streams_are_not_idle() {
+        let activity = activity();
+        let guard = StreamGuard::new(&activity);
+        let later = Instant::now() + IDLE_TIMEOUT * 2;
+        assert!(!activity.lock().unwrap().is_idle(later));
+
+        drop(guard);
+        let activity = activity.lock().unwrap();
+        assert_eq!(activity.open_streams, 0);
+        assert!(!activity.is_idle(Instant::now()));
+        assert!(activity.is_idle(activity.last_used + IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn streams_are_counted_until_dropped() {
+        let activity = activity();
+        let first = StreamGuard::new(&activity);
+        let second = StreamGuard::new(&activity);
+        assert_eq!(activity.lock().unwrap().open_streams, 2);
+        drop(first);
+        assert_eq!(activity.lock().unwrap().open_streams, 1);
+        drop(second);
+        assert_eq!(activity.lock().unwrap().open_streams, 0);
+    }
+}
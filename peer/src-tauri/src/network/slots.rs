@@ -0,0 +1,356 @@
+use iroh::{NodeAddr, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Duration;
+use tracing::debug;
+
+use crate::network::protocol::{client, UnsupportedRequest};
+use crate::network::sessions::Sessions;
+use crate::settings::{SharedSettings, UploadSettings};
+
+/// Queued peers check the settings again this often, so added slots are handed out
+/// without waiting for a running upload to finish.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where a peer stands with our upload slots, as reported over the Hermes protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotStatus {
+    /// The peer holds an upload slot.
+    Uploading,
+    /// The peer waits for a slot, position 1 is served next.
+    Queued { position: u32 },
+    /// The peer neither holds nor waits for a slot.
+    Idle,
+}
+
+/// Who holds and waits for our upload slots, as returned by `get_upload_slots`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlotsSnapshot {
+    pub limits: UploadSettings,
+    pub uploading: Vec<NodeId>,
+    /// Waiting peers, first in line first.
+    pub queued: Vec<NodeId>,
+}
+
+/// Limits how many peers download from us at once.
+///
+/// A peer takes a slot when it requests a blob and keeps it until its last transfer
+/// completes or aborts. Peers that find all slots taken wait in a FIFO queue with
+/// their request held back, and are told their position whenever it changes.
+/// Favourites may also take one of the extra slots reserved for them, so they can
+/// skip peers that wait for a regular slot.
+#[derive(Debug, Clone)]
+pub struct UploadSlots {
+    settings: SharedSettings,
+    inner: Arc<Mutex<Inner>>,
+    /// Notified when slots are handed out.
+    granted: Arc<Notify>,
+    /// New status of peers that are or were waiting in the queue.
+    updates: broadcast::Sender<(NodeId, SlotStatus)>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    limits: UploadSettings,
+    active: HashMap<NodeId, Slot>,
+    queue: VecDeque<Waiter>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    requests: usize,
+    /// Whether this is one of the slots reserved for favourites.
+    reserved: bool,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    node_id: NodeId,
+    favourite: bool,
+    requests: usize,
+    /// Position the peer was last told about.
+    reported: Option<u32>,
+}
+
+impl Inner {
+    /// Hands free slots to the waiting peers in order, returns whether any were given.
+    ///
+    /// Peers whose status changed are added to `updates`.
+    fn grant(&mut self, updates: &mut Vec<(NodeId, SlotStatus)>) -> bool {
+        let mut granted = false;
+        let mut i = 0;
+        while i < self.queue.len() {
+            let reserved = self.active.values().filter(|slot| slot.reserved).count();
+            let regular = self.active.len() - reserved;
+            let slot = if regular < self.limits.slots {
+                Some(false)
+            } else if self.queue[i].favourite && reserved < self.limits.favourite_slots {
+                Some(true)
+            } else {
+                None
+            };
+            match slot {
+                Some(reserved) => {
+                    let waiter = self.queue.remove(i).expect("index checked above");
+                    self.active.insert(
+                        waiter.node_id,
+                        Slot {
+                            requests: waiter.requests,
+                            reserved,
+                        },
+                    );
+                    if waiter.reported.is_some() {
+                        updates.push((waiter.node_id, SlotStatus::Uploading));
+                    }
+                    granted = true;
+                }
+                None => i += 1,
+            }
+        }
+        for (i, waiter) in self.queue.iter_mut().enumerate() {
+            let position = i as u32 + 1;
+            if waiter.reported != Some(position) {
+                waiter.reported = Some(position);
+                updates.push((waiter.node_id, SlotStatus::Queued { position }));
+            }
+        }
+        granted
+    }
+
+    fn release(&mut self, node_id: NodeId) {
+        if let Some(i) = self.queue.iter().position(|w| w.node_id == node_id) {
+            self.queue[i].requests -= 1;
+            if self.queue[i].requests == 0 {
+                self.queue.remove(i);
+            }
+        } else if let Some(slot) = self.active.get_mut(&node_id) {
+            slot.requests -= 1;
+            if slot.requests == 0 {
+                self.active.remove(&node_id);
+            }
+        }
+    }
+}
+
+impl UploadSlots {
+    pub fn new(settings: SharedSettings) -> Self {
+        let (updates, _) = broadcast::channel(64);
+        Self {
+            settings,
+            inner: Arc::new(Mutex::new(Inner::default())),
+            granted: Arc::new(Notify::new()),
+            updates,
+        }
+    }
+
+    /// Sends queued peers their new status over the Hermes protocol whenever it
+    /// changes, so they do not have to poll for it.
+    pub fn notify_peers(&self, sessions: Sessions) {
+        let mut updates = self.updates.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let (node_id, status) = match updates.recv().await {
+                    Ok(update) => update,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let sessions = sessions.clone();
+                tokio::spawn(async move {
+                    let result =
+                        client::send_slot_status(&sessions, NodeAddr::new(node_id), status).await;
+                    match result {
+                        // Older peers ask for their status instead
+                        Err(err) if err.is::<UnsupportedRequest>() => {}
+                        Err(err) => debug!("Could not update slot status of {node_id}: {err:#}"),
+                        Ok(()) => {}
+                    }
+                });
+            }
+        });
+    }
+
+    /// Hands out free slots and reports the peers whose status changed.
+    fn grant(&self, inner: &mut Inner) {
+        let mut updates = Vec::new();
+        if inner.grant(&mut updates) {
+            self.granted.notify_waiters();
+        }
+        for update in updates {
+            // Nobody listens before the endpoint is up
+            let _ = self.updates.send(update);
+        }
+    }
+
+    /// Waits until `node_id` holds a slot, which it keeps until the returned guard and
+    /// every other guard of the peer are dropped.
+    pub async fn acquire(&self, node_id: NodeId, favourite: bool) -> SlotGuard {
+        let limits = self.settings.lock().await.uploads.clone();
+        let guard = {
+            let mut inner = self.inner.lock().expect("poisoned");
+            inner.limits = limits;
+            let guard = SlotGuard {
+                slots: self.clone(),
+                node_id,
+            };
+            if let Some(slot) = inner.active.get_mut(&node_id) {
+                slot.requests += 1;
+                return guard;
+            }
+            match inner.queue.iter_mut().find(|w| w.node_id == node_id) {
+                Some(waiter) => waiter.requests += 1,
+                None => inner.queue.push_back(Waiter {
+                    node_id,
+                    favourite,
+                    requests: 1,
+                    reported: None,
+                }),
+            }
+            guard
+        };
+
+        loop {
+            let granted = self.granted.notified();
+            tokio::pin!(granted);
+            granted.as_mut().enable();
+            {
+                let mut inner = self.inner.lock().expect("poisoned");
+                self.grant(&mut inner);
+                if inner.active.contains_key(&node_id) {
+                    return guard;
+                }
+            }
+            let _ = tokio::time::timeout(RECHECK_INTERVAL, granted).await;
+            let limits = self.settings.lock().await.uploads.clone();
+            self.inner.lock().expect("poisoned").limits = limits;
+        }
+    }
+
+    pub fn status(&self, node_id: NodeId) -> SlotStatus {
+        let inner = self.inner.lock().expect("poisoned");
+        if inner.active.contains_key(&node_id) {
+            return SlotStatus::Uploading;
+        }
+        match inner.queue.iter().position(|w| w.node_id == node_id) {
+            Some(i) => SlotStatus::Queued {
+                position: i as u32 + 1,
+            },
+            None => SlotStatus::Idle,
+        }
+    }
+
+    pub fn snapshot(&self) -> SlotsSnapshot {
+        let inner = self.inner.lock().expect("poisoned");
+        SlotsSnapshot {
+            limits: inner.limits.clone(),
+            uploading: inner.active.keys().copied().collect(),
+            queued: inner.queue.iter().map(|w| w.node_id).collect(),
+        }
+    }
+}
+
+/// A request's claim on its peer's slot, or on its place in the queue while
+/// [`UploadSlots::acquire`] is still waiting.
+#[derive(Debug)]
+pub struct SlotGuard {
+    slots: UploadSlots,
+    node_id: NodeId,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let Ok(mut inner) = self.slots.inner.lock() else {
+            return;
+        };
+        inner.release(self.node_id);
+        self.slots.grant(&mut inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(n: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[n; 32]).public()
+    }
+
+    fn inner(slots: usize, favourite_slots: usize) -> Inner {
+        Inner {
+            limits: UploadSettings {
+                slots,
+                favourite_slots,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn enqueue(inner: &mut Inner, node_id: NodeId, favourite: bool) {
+        inner.queue.push_back(Waiter {
+            node_id,
+            favourite,
+            requests: 1,
+            reported: None,
+        });
+    }
+
+    #[test]
+    fn grants_slots_in_order() {
+        let mut inner = inner(2, 0);
+        for n in 1..=3 {
+            enqueue(&mut inner, node(n), false);
+        }
+        let mut updates = Vec::new();
+        assert!(inner.grant(&mut updates));
+        assert!(inner.active.contains_key(&node(1)));
+        assert!(inner.active.contains_key(&node(2)));
+        assert_eq!(updates, vec![(node(3), SlotStatus::Queued { position: 1 })]);
+
+        inner.release(node(1));
+        let mut updates = Vec::new();
+        assert!(inner.grant(&mut updates));
+        assert!(inner.active.contains_key(&node(3)));
+        assert!(inner.queue.is_empty());
+        assert_eq!(updates, vec![(node(3), SlotStatus::Uploading)]);
+    }
+
+    #[test]
+    fn favourites_skip_the_line_with_extra_slots() {
+        let mut inner = inner(1, 1);
+        enqueue(&mut inner, node(1), false);
+        enqueue(&mut inner, node(2), false);
+        enqueue(&mut inner, node(3), true);
+        inner.grant(&mut Vec::new());
+        assert!(inner.active.contains_key(&node(1)));
+        assert!(inner.active[&node(3)].reserved);
+        assert_eq!(inner.queue.len(), 1);
+        assert_eq!(inner.queue[0].node_id, node(2));
+    }
+
+    #[test]
+    fn extra_slots_are_only_for_favourites() {
+        let mut inner = inner(0, 2);
+        enqueue(&mut inner, node(1), false);
+        assert!(!inner.grant(&mut Vec::new()));
+        assert!(inner.active.is_empty());
+    }
+
+    #[test]
+    fn reports_positions_only_when_they_change() {
+        let mut inner = inner(0, 0);
+        enqueue(&mut inner, node(1), false);
+        enqueue(&mut inner, node(2), false);
+        let mut updates = Vec::new();
+        inner.grant(&mut updates);
+        assert_eq!(updates.len(), 2);
+
+        let mut updates = Vec::new();
+        inner.grant(&mut updates);
+        assert!(updates.is_empty());
+
+        inner.release(node(1));
+        inner.grant(&mut updates);
+        assert_eq!(updates, vec![(node(2), SlotStatus::Queued { position: 1 })]);
+    }
+}
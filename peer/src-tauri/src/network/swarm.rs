@@ -0,0 +1,320 @@
+use anyhow::Result;
+use bao_tree::io::BaoContentItem;
+use bao_tree::{ChunkNum, ChunkRanges};
+use iroh::endpoint::{Connection, Endpoint};
+use iroh::NodeId;
+use iroh_blobs::get::fsm::{self, BlobContentNext, ConnectedNext, EndBlobNext};
+use iroh_blobs::protocol::{GetRequest, RangeSpecSeq};
+use iroh_blobs::store::{fs::Store, BaoBatchWriter, MapEntryMut, MapMut};
+use iroh_blobs::{Hash, HashAndFormat};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use tauri::{AppHandle, Listener};
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{info, instrument, warn};
+
+use crate::network::bandwidth::{Bandwidth, Flow};
+use crate::network::protocol::BlobsClient;
+use crate::network::search::find_providers;
+use crate::network::sessions::Sessions;
+use crate::network::transfer::{download_tag, export_blob, ProgressReporter};
+use crate::state::{Peer, PeerSerializable};
+use crate::utils::safe_join;
+
+/// Size of a BLAKE3 chunk, the unit of verified ranges.
+const CHUNK_SIZE: u64 = 1024;
+/// Number of chunks fetched from one peer in a single request, 16 MiB.
+const PIECE_CHUNKS: u64 = 16 * 1024;
+
+type Entry = <Store as MapMut>::EntryMut;
+
+/// A range of chunks fetched from a single provider.
+#[derive(Debug, Clone)]
+struct Piece {
+    ranges: ChunkRanges,
+    /// Number of bytes covered by `ranges`.
+    len: u64,
+}
+
+/// A peer sharing the blob, with one connection used for all of its pieces.
+#[derive(Debug, Clone)]
+struct Provider {
+    peer: Peer,
+    connection: Arc<OnceCell<Connection>>,
+}
+
+impl Provider {
+    fn new(peer: Peer) -> Self {
+        Self {
+            peer,
+            connection: Arc::new(OnceCell::new()),
+        }
+    }
+
+    fn node_id(&self) -> NodeId {
+        self.peer.node_addr.node_id
+    }
+
+    /// The blobs connection to the peer, opened on first use.
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Connection> {
+        self.connection
+            .get_or_try_init(|| async {
+                endpoint
+                    .connect(self.peer.node_addr.clone(), iroh_blobs::ALPN)
+                    .await
+            })
+            .await
+            .cloned()
+    }
+}
+
+/// Everything a swarm download needs, cloned out of the `AppState`.
+pub struct SwarmContext {
+    pub endpoint: Endpoint,
+    pub sessions: Sessions,
+    pub store: Store,
+    pub blobs_client: BlobsClient,
+    pub peers: Vec<Peer>,
+    pub bandwidth: Bandwidth,
+    pub app_handle: AppHandle,
+}
+
+/// Downloads the file with content `hash` from every peer sharing it at once and
+/// exports it to `destination/<name>`.
+///
+/// The blob is split into pieces which are handed out to providers as they become
+/// idle. A piece that fails, or whose provider leaves the network, is queued again for
+/// the remaining providers.
+#[instrument(skip(ctx, progress), err)]
+pub async fn swarm_download(
+    ctx: SwarmContext,
+    hash: Hash,
+    name: &str,
+    destination: &Path,
+    progress: &mut ProgressReporter,
+) -> Result<()> {
+    anyhow::ensure!(destination.is_absolute(), "Destination must be absolute");
+    // The name comes from a search result
+    let target = safe_join(destination, name)?;
+    let mut providers: Vec<Provider> = find_providers(&ctx.sessions, ctx.peers.clone(), name, hash)
+        .await
+        .into_iter()
+        .map(Provider::new)
+        .collect();
+    anyhow::ensure!(!providers.is_empty(), "No peer is sharing {name}");
+    info!("Found {} providers for {name}", providers.len());
+
+    let size = blob_size(&ctx.endpoint, &providers, hash).await?;
+    progress.set_total(size);
+    let mut pieces = split_into_pieces(size);
+
+    ctx.blobs_client
+        .tags()
+        .set(download_tag(&hash), HashAndFormat::raw(hash))
+        .await?;
+    let entry = ctx.store.get_or_create(hash, size).await?;
+
+    // Forward `peer::left` so the providers that left are dropped right away
+    let (left_tx, mut left_rx) = mpsc::unbounded_channel::<NodeId>();
+    let listener = ctx.app_handle.listen("peer::left", move |event| {
+        if let Ok(peer) = serde_json::from_str::<PeerSerializable>(event.payload()) {
+            let _ = left_tx.send(peer.node_id);
+        }
+    });
+
+    let mut tasks = JoinSet::new();
+    let mut in_flight: HashMap<tokio::task::Id, (Provider, Piece, AbortHandle)> = HashMap::new();
+    let mut bytes_done = 0;
+    let result = loop {
+        while !pieces.is_empty() && !providers.is_empty() {
+            let provider = providers.remove(0);
+            let piece = pieces.pop_front().expect("checked above");
+            let handle = tasks.spawn(fetch_piece(
+                ctx.endpoint.clone(),
+                ctx.bandwidth.clone(),
+                entry.clone(),
+                provider.clone(),
+                hash,
+                piece.clone(),
+            ));
+            in_flight.insert(handle.id(), (provider, piece, handle));
+        }
+        if in_flight.is_empty() {
+            if pieces.is_empty() {
+                break Ok(());
+            }
+            break Err(anyhow::anyhow!("All providers of {name} failed or left"));
+        }
+
+        tokio::select! {
+            Some(joined) = tasks.join_next_with_id() => {
+                let (id, outcome) = match joined {
+                    Ok((id, outcome)) => (id, outcome),
+                    Err(err) => (err.id(), Err(anyhow::anyhow!("Piece download aborted"))),
+                };
+                let Some((provider, piece, _)) = in_flight.remove(&id) else {
+                    continue;
+                };
+                match outcome {
+                    Ok(piece_len) => {
+                        bytes_done += piece_len;
+                        progress.update(bytes_done);
+                        providers.push(provider);
+                    }
+                    Err(err) => {
+                        // Give the piece to someone else and stop asking this peer
+                        warn!("Dropping provider {}: {err:#}", provider.peer.username);
+                        pieces.push_front(piece);
+                    }
+                }
+            }
+            Some(node_id) = left_rx.recv() => {
+                providers.retain(|p| p.node_id() != node_id);
+                for (provider, _, handle) in in_flight.values() {
+                    if provider.node_id() == node_id {
+                        handle.abort();
+                    }
+                }
+            }
+        }
+    };
+    ctx.app_handle.unlisten(listener);
+    result?;
+
+    ctx.store.insert_complete(entry).await?;
+    export_blob(&ctx.blobs_client, hash, &target).await?;
+    ctx.blobs_client.tags().delete(download_tag(&hash)).await?;
+    Ok(())
+}
+
+fn split_into_pieces(size: u64) -> VecDeque<Piece> {
+    let chunks = size.div_ceil(CHUNK_SIZE).max(1);
+    (0..chunks)
+        .step_by(PIECE_CHUNKS as usize)
+        .map(|start| {
+            let end = (start + PIECE_CHUNKS).min(chunks);
+            Piece {
+                ranges: ChunkRanges::from(ChunkNum(start)..ChunkNum(end)),
+                len: (end * CHUNK_SIZE).min(size) - start * CHUNK_SIZE,
+            }
+        })
+        .collect()
+}
+
+/// Asks the providers in turn for the size of the blob, verified by its last chunk.
+async fn blob_size(endpoint: &Endpoint, providers: &[Provider], hash: Hash) -> Result<u64> {
+    for provider in providers {
+        match request_size(endpoint, provider, hash).await {
+            Ok(size) => return Ok(size),
+            Err(err) => warn!(
+                "Could not get size from {}: {err:#}",
+                provider.peer.username
+            ),
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No provider could report the size of {hash}"
+    ))
+}
+
+async fn request_size(endpoint: &Endpoint, provider: &Provider, hash: Hash) -> Result<u64> {
+    let connection = provider.connect(endpoint).await?;
+    let connected = fsm::start(connection, GetRequest::last_chunk(hash))
+        .next()
+        .await?;
+    let ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("Unexpected response to size request");
+    };
+    let (content, size) = start.next().next().await?;
+    content.drain().await?;
+    Ok(size)
+}
+
+/// Fetches a single piece from one provider and writes it into the store.
+///
+/// The download limits are applied to every chunk group as it arrives, which holds
+/// back reading from the provider's stream.
+async fn fetch_piece(
+    endpoint: Endpoint,
+    bandwidth: Bandwidth,
+    entry: Entry,
+    provider: Provider,
+    hash: Hash,
+    piece: Piece,
+) -> Result<u64> {
+    let connection = provider.connect(&endpoint).await?;
+    let request = GetRequest::new(hash, RangeSpecSeq::from_ranges([piece.ranges]));
+    let connected = fsm::start(connection, request).next().await?;
+    let ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("Unexpected response to piece request");
+    };
+    let (mut content, size) = start.next().next().await?;
+    let mut writer = entry.batch_writer().await?;
+    let mut batch = Vec::new();
+    let end = loop {
+        match content.next().await {
+            BlobContentNext::More((next, item)) => {
+                let item = item?;
+                // Parents are written together with the leaf that follows them
+                if let BaoContentItem::Leaf(leaf) = &item {
+                    let len = leaf.data.len() as u64;
+                    batch.push(item);
+                    writer.write_batch(size, std::mem::take(&mut batch)).await?;
+                    bandwidth
+                        .consume(Flow::Download, provider.node_id(), len)
+                        .await;
+                } else {
+                    batch.push(item);
+                }
+                content = next;
+            }
+            BlobContentNext::Done(end) => break end,
+        }
+    };
+    writer.sync().await?;
+    let EndBlobNext::Closing(closing) = end.next() else {
+        anyhow::bail!("Unexpected trailing blobs");
+    };
+    closing.next().await?;
+    Ok(piece.len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_SIZE: u64 = PIECE_CHUNKS * CHUNK_SIZE;
+
+    #[test]
+    fn pieces_cover_the_whole_blob() {
+        let size = 2 * PIECE_SIZE + 100;
+        let pieces = split_into_pieces(size);
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces.iter().map(|p| p.len).sum::<u64>(), size);
+        assert_eq!(pieces[2].len, 100);
+        assert_eq!(
+            pieces[1].ranges,
+            ChunkRanges::from(ChunkNum(PIECE_CHUNKS)..ChunkNum(2 * PIECE_CHUNKS))
+        );
+    }
+
+    #[test]
+    fn pieces_end_on_the_last_chunk() {
+        let pieces = split_into_pieces(PIECE_SIZE);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].len, PIECE_SIZE);
+    }
+
+    #[test]
+    fn empty_blob_is_one_piece() {
+        let pieces = split_into_pieces(0);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].len, 0);
+        assert_eq!(
+            pieces[0].ranges,
+            ChunkRanges::from(ChunkNum(0)..ChunkNum(1))
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use futures_lite::StreamExt;
+use iroh::NodeAddr;
+use iroh_blobs::ticket::BlobTicket;
+use iroh_blobs::{BlobFormat, Hash, Tag};
+
+use crate::network::protocol::{BlobsClient, TreeNode};
+
+/// Collections created for tickets are tagged `hermes-ticket/<share tag>/<hash>`, so
+/// they are kept alive while the share exists and dropped together with it.
+const TICKET_TAG_PREFIX: &str = "hermes-ticket/";
+
+pub fn is_ticket_tag(tag: &Tag) -> bool {
+    tag.0.starts_with(TICKET_TAG_PREFIX.as_bytes())
+}
+
+pub(crate) fn ticket_tag(share_tag: &str, hash: &Hash) -> Tag {
+    Tag::from(format!("{TICKET_TAG_PREFIX}{share_tag}/{hash}"))
+}
+
+/// The tag of the share a ticket tag was created for, `None` for other tags.
+pub(crate) fn ticket_share(tag: &Tag) -> Option<String> {
+    let rest = tag.0.strip_prefix(TICKET_TAG_PREFIX.as_bytes())?;
+    let rest = String::from_utf8_lossy(rest);
+    rest.rsplit_once('/').map(|(share, _)| share.to_owned())
+}
+
+/// Deletes the tags of all tickets created for the share under `share_tag`.
+pub async fn delete_tickets(blobs_client: &BlobsClient, share_tag: &str) -> Result<()> {
+    let mut tags = blobs_client.tags().list().await?;
+    let mut stale = Vec::new();
+    while let Some(tag) = tags.next().await {
+        let tag = tag?.name;
+        if ticket_share(&tag).as_deref() == Some(share_tag) {
+            stale.push(tag);
+        }
+    }
+    for tag in stale {
+        blobs_client.tags().delete(tag).await?;
+    }
+    Ok(())
+}
+
+/// The provider and the node to download for a pasted ticket.
+///
+/// Tickets we create always point at a collection whose entries are named relative to
+/// the shared node's parent, so exporting the whole collection recreates the node.
+/// Plain blob tickets from other iroh tools are saved under their hash.
+///
+/// The collection may come from anyone, so the empty `path` selects every entry and
+/// each entry name goes through the same check as listed files when it is exported,
+/// see `transfer::download`.
+pub fn ticket_target(ticket: &BlobTicket) -> (NodeAddr, TreeNode) {
+    let hash = ticket.hash();
+    let children = match ticket.format() {
+        BlobFormat::HashSeq => Some(Vec::new()),
+        BlobFormat::Raw => None,
+    };
+    let node = TreeNode {
+        id: hash.to_string(),
+        name: hash.to_string(),
+        hash: hash.to_string(),
+        path: String::new(),
+        size: None,
+        modified: None,
+        children,
+    };
+    (ticket.node_addr().clone(), node)
+}
@@ -0,0 +1,431 @@
+use anyhow::{Context, Result};
+use bao_tree::io::BaoContentItem;
+use bao_tree::ChunkRanges;
+use iroh::endpoint::Connection;
+use iroh::NodeAddr;
+use iroh_blobs::format::collection::Collection;
+use iroh_blobs::get::db::valid_ranges;
+use iroh_blobs::get::fsm::{self, BlobContentNext, ConnectedNext, EndBlobNext};
+use iroh_blobs::hashseq::HashSeq;
+use iroh_blobs::net_protocol::Blobs;
+use iroh_blobs::protocol::{GetRequest, RangeSpecSeq};
+use iroh_blobs::store::fs::Store;
+use iroh_blobs::store::{BaoBatchWriter, ExportFormat, ExportMode, MapEntry, MapEntryMut, MapMut};
+use iroh_blobs::{Hash, HashAndFormat, Tag};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tauri::{AppHandle, Emitter};
+use tokio::time::{Duration, Instant};
+use tracing::{info, instrument, trace, warn};
+
+use crate::network::bandwidth::{Bandwidth, Flow};
+use crate::network::protocol::{BlobsClient, ProgressMessage, TreeNode};
+use crate::utils::safe_join;
+
+/// Minimum time between two `transfer::progress` events for the same transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Tags of downloaded blobs live under this prefix so they are never mistaken for
+/// our own shares, whose tags are absolute paths.
+const DOWNLOAD_TAG_PREFIX: &str = "hermes-download/";
+
+pub fn is_download_tag(tag: &Tag) -> bool {
+    tag.0.starts_with(DOWNLOAD_TAG_PREFIX.as_bytes())
+}
+
+pub(crate) fn download_tag(hash: &Hash) -> Tag {
+    Tag::from(format!("{DOWNLOAD_TAG_PREFIX}{hash}"))
+}
+
+/// The blobs a download of `node` tags, as far as the collection is known locally.
+pub async fn download_hashes(blobs_client: &BlobsClient, node: &TreeNode) -> Result<Vec<Hash>> {
+    let hash = Hash::from_str(&node.hash).context("Invalid hash")?;
+    let mut hashes = vec![hash];
+    if node.children.is_some() {
+        // The collection may not have arrived before the download was stopped
+        if let Ok((meta, collection)) = local_collection(blobs_client, hash).await {
+            hashes.push(meta);
+            let entries = collection.iter().map(|(name, hash)| (name.as_str(), *hash));
+            if let Ok(targets) = export_targets(entries, &node.path, Path::new("/")) {
+                hashes.extend(targets.into_iter().map(|(_, hash)| hash));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Drops the tags protecting the partially downloaded data of `hashes`.
+pub async fn discard(
+    blobs_client: &BlobsClient,
+    hashes: impl IntoIterator<Item = Hash>,
+) -> Result<()> {
+    for hash in hashes {
+        blobs_client.tags().delete(download_tag(&hash)).await?;
+    }
+    Ok(())
+}
+
+/// Download a file or directory listed by a remote peer and export it into `destination`.
+///
+/// File nodes are fetched as a single raw blob and written as `destination/<name>`.
+/// Directory nodes carry the hash of the collection of their whole share. Only the
+/// collection itself and the files below the node's path are fetched, and exported
+/// keeping their path relative to the node's parent, so downloading `a/b` produces
+/// `destination/b/...`.
+///
+/// Names come from the peer, so the download fails before anything is exported if
+/// one of them would leave `destination`, see [`safe_join`].
+///
+/// Every blob fetched is tagged with a tag derived from its hash until it is exported,
+/// so calling this again after an interruption only fetches the missing ranges.
+///
+/// The download limits are applied by reading from the connection only as fast as
+/// `bandwidth` allows, so the provider is held back by flow control.
+#[instrument(skip(blobs, bandwidth, progress), err)]
+pub async fn download(
+    blobs: &Blobs<Store>,
+    bandwidth: &Bandwidth,
+    node_addr: NodeAddr,
+    node: &TreeNode,
+    destination: &Path,
+    progress: &mut ProgressReporter,
+) -> Result<()> {
+    anyhow::ensure!(destination.is_absolute(), "Destination must be absolute");
+    let hash = Hash::from_str(&node.hash).context("Invalid hash")?;
+    let blobs_client = blobs.client();
+    let mut fetcher = Fetcher {
+        blobs,
+        bandwidth,
+        node_addr,
+        connection: None,
+        progress,
+        bytes_done: 0,
+    };
+
+    if node.children.is_none() {
+        let target = safe_join(destination, &node.name)?;
+        fetcher.fetch(hash).await?;
+        export_blob(blobs_client, hash, &target).await?;
+        blobs_client.tags().delete(download_tag(&hash)).await?;
+        info!("Downloaded {}", node.name);
+        return Ok(());
+    }
+
+    fetcher.fetch(hash).await?;
+    let meta = collection_meta(blobs_client, hash).await?;
+    fetcher.fetch(meta).await?;
+    let collection = blobs_client.get_collection(hash).await?;
+    let entries = collection.iter().map(|(name, hash)| (name.as_str(), *hash));
+    let targets = export_targets(entries, &node.path, destination)?;
+    anyhow::ensure!(!targets.is_empty(), "{} has no files", node.name);
+
+    for (_, blob_hash) in &targets {
+        fetcher.fetch(*blob_hash).await?;
+    }
+    for (target, blob_hash) in &targets {
+        export_blob(blobs_client, *blob_hash, target).await?;
+    }
+    for blob_hash in [hash, meta]
+        .into_iter()
+        .chain(targets.iter().map(|(_, hash)| *hash))
+    {
+        blobs_client.tags().delete(download_tag(&blob_hash)).await?;
+    }
+    info!("Downloaded {} ({} files)", node.name, targets.len());
+    Ok(())
+}
+
+/// Fetches single blobs from one provider over one connection, adding up their
+/// progress.
+struct Fetcher<'a> {
+    blobs: &'a Blobs<Store>,
+    bandwidth: &'a Bandwidth,
+    node_addr: NodeAddr,
+    /// Opened by the first blob that is not present yet.
+    connection: Option<Connection>,
+    progress: &'a mut ProgressReporter,
+    /// Bytes present so far, over all blobs fetched.
+    bytes_done: u64,
+}
+
+impl Fetcher<'_> {
+    /// Fetches the ranges of `hash` we do not have yet, tagging the blob first so the
+    /// ranges already fetched survive an interruption.
+    async fn fetch(&mut self, hash: Hash) -> Result<()> {
+        self.blobs
+            .client()
+            .tags()
+            .set(download_tag(&hash), HashAndFormat::raw(hash))
+            .await?;
+        let store = self.blobs.store();
+        let missing = match store.get_mut(&hash).await? {
+            Some(entry) if entry.is_complete() => {
+                let size = entry.size().value();
+                self.bytes_done += size;
+                self.progress.add_total(size);
+                self.progress.update(self.bytes_done);
+                return Ok(());
+            }
+            Some(entry) => ChunkRanges::all().difference(&valid_ranges::<Store>(&entry).await?),
+            None => ChunkRanges::all(),
+        };
+
+        let node_id = self.node_addr.node_id;
+        let connection = self.connect().await?;
+        let request = GetRequest::new(hash, RangeSpecSeq::from_ranges([missing]));
+        let connected = fsm::start(connection, request).next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            anyhow::bail!("Unexpected response to blob request");
+        };
+        let (mut content, size) = start.next().next().await?;
+        self.progress.add_total(size);
+        let bytes_before = self.bytes_done;
+
+        let entry = store.get_or_create(hash, size).await?;
+        let mut writer = entry.batch_writer().await?;
+        let mut batch = Vec::new();
+        let end = loop {
+            match content.next().await {
+                BlobContentNext::More((next, item)) => {
+                    let item = item?;
+                    // Parents are written together with the leaf that follows them
+                    if let BaoContentItem::Leaf(leaf) = &item {
+                        let len = leaf.data.len() as u64;
+                        batch.push(item);
+                        writer.write_batch(size, std::mem::take(&mut batch)).await?;
+                        self.bytes_done += len;
+                        self.progress.update(self.bytes_done);
+                        // Not reading on holds the provider back
+                        self.bandwidth.consume(Flow::Download, node_id, len).await;
+                    } else {
+                        batch.push(item);
+                    }
+                    content = next;
+                }
+                BlobContentNext::Done(end) => break end,
+            }
+        };
+        writer.sync().await?;
+        let EndBlobNext::Closing(closing) = end.next() else {
+            anyhow::bail!("Unexpected trailing blobs");
+        };
+        let stats = closing.next().await?;
+        store.insert_complete(entry).await?;
+        trace!(
+            "Fetched {hash} ({} bytes read in {:?})",
+            stats.bytes_read,
+            stats.elapsed
+        );
+        // Ranges that were present before count as done too
+        self.bytes_done = bytes_before + size;
+        self.progress.update(self.bytes_done);
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<Connection> {
+        if let Some(connection) = &self.connection {
+            return Ok(connection.clone());
+        }
+        let connection = self
+            .blobs
+            .endpoint()
+            .connect(self.node_addr.clone(), iroh_blobs::ALPN)
+            .await?;
+        self.connection = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+/// The hash of the blob holding the names of the collection `hash`, which has to be
+/// present already.
+pub(crate) async fn collection_meta(blobs_client: &BlobsClient, hash: Hash) -> Result<Hash> {
+    let hash_seq = HashSeq::try_from(blobs_client.read_to_bytes(hash).await?)?;
+    hash_seq.iter().next().context("Collection has no metadata")
+}
+
+async fn local_collection(blobs_client: &BlobsClient, hash: Hash) -> Result<(Hash, Collection)> {
+    let meta = collection_meta(blobs_client, hash).await?;
+    Ok((meta, blobs_client.get_collection(hash).await?))
+}
+
+/// Where each entry of a collection below `root` is exported to, keeping its path
+/// relative to the parent of `root`. An empty `root` selects every entry.
+fn export_targets<'a>(
+    entries: impl IntoIterator<Item = (&'a str, Hash)>,
+    root: &str,
+    destination: &Path,
+) -> Result<Vec<(PathBuf, Hash)>> {
+    let root = Path::new(root);
+    let parent = root.parent().unwrap_or_else(|| Path::new(""));
+    let mut targets = Vec::new();
+    for (name, hash) in entries {
+        let name = Path::new(name);
+        if !name.starts_with(root) {
+            continue;
+        }
+        let relative = name.strip_prefix(parent)?;
+        targets.push((safe_join(destination, relative)?, hash));
+    }
+    Ok(targets)
+}
+
+pub(crate) async fn export_blob(
+    blobs_client: &BlobsClient,
+    hash: Hash,
+    target: &Path,
+) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    blobs_client
+        .export(
+            hash,
+            target.to_path_buf(),
+            ExportFormat::Blob,
+            ExportMode::Copy,
+        )
+        .await?
+        .finish()
+        .await
+        .with_context(|| format!("Failed to export {}", target.display()))?;
+    trace!("Exported {}", target.display());
+    Ok(())
+}
+
+/// Emits throttled `transfer::progress` events for a single import or download and a
+/// final `transfer::completed` or `transfer::failed` event.
+pub struct ProgressReporter {
+    app: AppHandle,
+    file_name: String,
+    bytes_done: u64,
+    total_bytes: u64,
+    last_emitted: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferFailed {
+    pub file_name: String,
+    pub error: String,
+}
+
+impl ProgressReporter {
+    pub fn new(app: AppHandle, file_name: impl Into<String>) -> Self {
+        Self {
+            app,
+            file_name: file_name.into(),
+            bytes_done: 0,
+            total_bytes: 0,
+            last_emitted: None,
+        }
+    }
+
+    pub fn set_total(&mut self, total_bytes: u64) {
+        self.total_bytes = total_bytes;
+    }
+
+    pub fn add_total(&mut self, bytes: u64) {
+        self.total_bytes += bytes;
+    }
+
+    /// Records the number of bytes transferred so far, emitting an event at most
+    /// once every [`PROGRESS_INTERVAL`].
+    pub fn update(&mut self, bytes_done: u64) {
+        self.bytes_done = bytes_done;
+        let due = self
+            .last_emitted
+            .is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL);
+        if due {
+            self.emit_progress();
+        }
+    }
+
+    pub fn completed(mut self) {
+        self.bytes_done = self.total_bytes;
+        self.emit_progress();
+        let _ = self.app.emit("transfer::completed", self.message());
+    }
+
+    pub fn failed(self, err: &anyhow::Error) {
+        warn!("Transfer of {} failed: {err:#}", self.file_name);
+        let payload = TransferFailed {
+            file_name: self.file_name.clone(),
+            error: format!("{err:#}"),
+        };
+        let _ = self.app.emit("transfer::failed", payload);
+    }
+
+    fn emit_progress(&mut self) {
+        self.last_emitted = Some(Instant::now());
+        let _ = self.app.emit("transfer::progress", self.message());
+    }
+
+    fn message(&self) -> ProgressMessage {
+        let percentage = if self.total_bytes == 0 {
+            100.0
+        } else {
+            (self.bytes_done as f64 / self.total_bytes as f64 * 100.0) as f32
+        };
+        ProgressMessage {
+            file_name: self.file_name.clone(),
+            bytes_uploaded: self.bytes_done,
+            total_bytes: self.total_bytes,
+            percentage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> Hash {
+        Hash::new([n])
+    }
+
+    #[test]
+    fn exports_subtree_relative_to_parent() {
+        let entries = [
+            ("share/a/x.txt", hash(1)),
+            ("share/a/b/y.txt", hash(2)),
+            ("share/c.txt", hash(3)),
+        ];
+        let targets = export_targets(entries, "share/a", Path::new("/dl")).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                (PathBuf::from("/dl/a/x.txt"), hash(1)),
+                (PathBuf::from("/dl/a/b/y.txt"), hash(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ticket_collections_export_every_entry() {
+        let entries = [("notes/a.txt", hash(1)), ("b.txt", hash(2))];
+        let targets = export_targets(entries, "", Path::new("/dl")).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                (PathBuf::from("/dl/notes/a.txt"), hash(1)),
+                (PathBuf::from("/dl/b.txt"), hash(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_names_leaving_destination() {
+        for name in [
+            "../evil",
+            "/etc/passwd",
+            "notes/../../evil",
+            "notes/./../evil",
+        ] {
+            let entries = [("notes/ok.txt", hash(1)), (name, hash(2))];
+            assert!(
+                export_targets(entries, "", Path::new("/dl")).is_err(),
+                "{name} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::global::APP_DATA_DIR;
+
+const SETTINGS_FILE: &str = "settings.json";
+/// Version of the settings file written by this build. Older files are migrated on
+/// load, see [`migrate`].
+pub const SETTINGS_VERSION: u32 = 2;
+/// Highest bandwidth limit accepted, 100 GiB/s. Anything faster is as good as no limit.
+const MAX_BANDWIDTH_LIMIT_KIB: u64 = 100 * 1024 * 1024;
+
+/// The settings of the running app. Components keep a clone of this handle and read
+/// it whenever they need a value, so updates apply without a restart.
+pub type SharedSettings = Arc<Mutex<Settings>>;
+
+/// User adjustable settings, persisted as JSON under `APP_DATA_DIR`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    /// Folder the download dialogs open in, the user's choice if `None`.
+    pub download_dir: Option<PathBuf>,
+    /// Number of files hashed concurrently when sharing.
+    pub io_parallelism: usize,
+    pub bandwidth: BandwidthSettings,
+    pub uploads: UploadSettings,
+    pub discovery: DiscoverySettings,
+    pub privacy: PrivacySettings,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthSettings {
+    /// Cap on the rate blobs are served to peers, in KiB/s. `None` is unlimited.
+    pub upload_limit_kib: Option<u64>,
+    /// Cap on the rate blobs are downloaded from peers, in KiB/s. `None` is unlimited.
+    pub download_limit_kib: Option<u64>,
+    /// Like `upload_limit_kib`, but for each peer on its own.
+    pub peer_upload_limit_kib: Option<u64>,
+    /// Like `download_limit_kib`, but for each peer on its own.
+    pub peer_download_limit_kib: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadSettings {
+    /// Number of peers that may download from us at once, the others wait in line.
+    pub slots: usize,
+    /// Extra slots only favourites may take.
+    pub favourite_slots: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    /// A peer not announced by discovery for this long is shown as away.
+    pub timeout_secs: u64,
+    /// How often peers are checked against the timeout.
+    pub sweep_interval_secs: u64,
+    /// How long a peer stays away before `peer::left` is emitted and it is removed.
+    pub away_grace_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrivacySettings {
+    /// Answer file listing and search requests from peers.
+    pub share_listing: bool,
+    /// Accept direct messages from peers.
+    pub accept_messages: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            download_dir: None,
+            io_parallelism: 4,
+            bandwidth: BandwidthSettings::default(),
+            uploads: UploadSettings::default(),
+            discovery: DiscoverySettings::default(),
+            privacy: PrivacySettings::default(),
+        }
+    }
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self {
+            slots: 3,
+            favourite_slots: 0,
+        }
+    }
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            sweep_interval_secs: 5,
+            away_grace_secs: 60,
+        }
+    }
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            share_listing: true,
+            accept_messages: true,
+        }
+    }
+}
+
+impl DiscoverySettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval_secs)
+    }
+
+    pub fn away_grace(&self) -> Duration {
+        Duration::from_secs(self.away_grace_secs)
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.version == SETTINGS_VERSION,
+            "Unsupported settings version {}",
+            self.version
+        );
+        if let Some(dir) = &self.download_dir {
+            anyhow::ensure!(
+                dir.is_absolute(),
+                "Download folder must be an absolute path"
+            );
+        }
+        anyhow::ensure!(
+            (1..=64).contains(&self.io_parallelism),
+            "Import parallelism must be between 1 and 64"
+        );
+        for limit in [
+            self.bandwidth.upload_limit_kib,
+            self.bandwidth.download_limit_kib,
+            self.bandwidth.peer_upload_limit_kib,
+            self.bandwidth.peer_download_limit_kib,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            anyhow::ensure!(
+                (1..=MAX_BANDWIDTH_LIMIT_KIB).contains(&limit),
+                "Bandwidth limits must be between 1 KiB/s and 100 GiB/s"
+            );
+        }
+        anyhow::ensure!(
+            (1..=64).contains(&self.uploads.slots),
+            "Upload slots must be between 1 and 64"
+        );
+        anyhow::ensure!(
+            self.uploads.favourite_slots <= 64,
+            "Favourite slots must be at most 64"
+        );
+        let discovery = &self.discovery;
+        anyhow::ensure!(
+            discovery.timeout_secs > 0,
+            "Discovery timeout must be at least one second"
+        );
+        anyhow::ensure!(
+            discovery.sweep_interval_secs > 0,
+            "Sweep interval must be at least one second"
+        );
+        // Sweeping less often than the timeout lets peers flap between sweeps
+        anyhow::ensure!(
+            discovery.sweep_interval_secs <= discovery.timeout_secs,
+            "Sweep interval must not be longer than the discovery timeout"
+        );
+        Ok(())
+    }
+}
+
+/// Brings settings saved by an older version up to [`SETTINGS_VERSION`].
+///
+/// Version 1 had no `version` field and only the discovery options, at the top level.
+fn migrate(mut value: serde_json::Value) -> Result<serde_json::Value> {
+    let object = value
+        .as_object_mut()
+        .context("Settings must be an object")?;
+    let version = match object.get("version") {
+        Some(version) => version.as_u64().context("Invalid settings version")? as u32,
+        None => 1,
+    };
+    anyhow::ensure!(
+        version <= SETTINGS_VERSION,
+        "Settings were saved by a newer version ({version})"
+    );
+    if version < 2 {
+        let mut discovery = serde_json::Map::new();
+        for (old, new) in [
+            ("discovery_timeout_secs", "timeout_secs"),
+            ("sweep_interval_secs", "sweep_interval_secs"),
+            ("away_grace_secs", "away_grace_secs"),
+        ] {
+            if let Some(value) = object.remove(old) {
+                discovery.insert(new.to_owned(), value);
+            }
+        }
+        object.insert("discovery".to_owned(), discovery.into());
+    }
+    object.insert("version".to_owned(), SETTINGS_VERSION.into());
+    Ok(value)
+}
+
+/// The saved settings, or the defaults if none were saved or they are invalid.
+/// Settings of an older version are migrated and saved again.
+pub fn load() -> Settings {
+    let path = APP_DATA_DIR.join(SETTINGS_FILE);
+    let Ok(data) = std::fs::read(&path) else {
+        return Settings::default();
+    };
+    let loaded = serde_json::from_slice::<serde_json::Value>(&data)
+        .map_err(anyhow::Error::from)
+        .and_then(|value| {
+            let migrated = value.get("version") != Some(&SETTINGS_VERSION.into());
+            let settings: Settings = serde_json::from_value(migrate(value)?)?;
+            settings.validate()?;
+            Ok((settings, migrated))
+        });
+    match loaded {
+        Ok((settings, migrated)) => {
+            if migrated {
+                info!("Migrated settings to version {SETTINGS_VERSION}");
+                if let Err(err) = save_blocking(&settings) {
+                    warn!("Could not save migrated settings: {err:#}");
+                }
+            }
+            settings
+        }
+        Err(err) => {
+            warn!("Discarding unusable settings: {err:#}");
+            Settings::default()
+        }
+    }
+}
+
+fn save_blocking(settings: &Settings) -> Result<()> {
+    let data = serde_json::to_vec_pretty(settings)?;
+    std::fs::write(APP_DATA_DIR.join(SETTINGS_FILE), data).context("Failed to save settings")
+}
+
+pub async fn save(settings: &Settings) -> Result<()> {
+    let path = APP_DATA_DIR.join(SETTINGS_FILE);
+    let data = serde_json::to_vec_pretty(settings)?;
+    tokio::fs::write(&path, data)
+        .await
+        .context("Failed to save settings")?;
+    Ok(())
+}
+
+/// Validates and saves `settings`, applies them to the running app and emits
+/// `settings::changed` with the new settings.
+pub async fn update(
+    shared: &SharedSettings,
+    app: &AppHandle,
+    settings: Settings,
+) -> Result<Settings> {
+    settings.validate()?;
+    save(&settings).await?;
+    *shared.lock().await = settings.clone();
+    let _ = app.emit("settings::changed", settings.clone());
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrates_version_1_discovery_options() {
+        let value = json!({
+            "discovery_timeout_secs": 45,
+            "sweep_interval_secs": 10,
+            "away_grace_secs": 90,
+        });
+        let settings: Settings = serde_json::from_value(migrate(value).unwrap()).unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                discovery: DiscoverySettings {
+                    timeout_secs: 45,
+                    sweep_interval_secs: 10,
+                    away_grace_secs: 90,
+                },
+                ..Settings::default()
+            }
+        );
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn keeps_current_settings() {
+        let value = serde_json::to_value(Settings::default()).unwrap();
+        assert_eq!(migrate(value.clone()).unwrap(), value);
+    }
+
+    #[test]
+    fn refuses_newer_or_malformed_settings() {
+        assert!(migrate(json!({ "version": SETTINGS_VERSION + 1 })).is_err());
+        assert!(migrate(json!({ "version": "2" })).is_err());
+        assert!(migrate(json!([])).is_err());
+    }
+
+    #[test]
+    fn bounds_bandwidth_limits() {
+        let mut settings = Settings::default();
+        settings.bandwidth.upload_limit_kib = Some(MAX_BANDWIDTH_LIMIT_KIB);
+        settings.validate().unwrap();
+        settings.bandwidth.upload_limit_kib = Some(0);
+        assert!(settings.validate().is_err());
+        settings.bandwidth.upload_limit_kib = Some(1 << 54);
+        assert!(settings.validate().is_err());
+    }
+}
@@ -2,21 +2,48 @@ use anyhow::{Context, Result};
 use iroh::protocol::Router;
 use iroh::Endpoint;
 use std::sync::Arc;
-use tauri::AppHandle;
+use tauri::{AppHandle, Emitter};
 use tokio::sync::Mutex;
+use tracing::warn;
 
+use crate::identity;
+use crate::integrity;
+use crate::network::bandwidth::Bandwidth;
 use crate::network::discovery::run_discovery;
+use crate::network::favourites::{self, Favourite, Favourites};
+use crate::network::gate::{BlobsGate, GatedBlobs};
+use crate::network::moderation::Moderation;
+use crate::network::outbox::Outbox;
 use crate::network::protocol::FileProtocol;
 use crate::network::protocol::ALPN;
+use crate::network::queue::TransferManager;
+use crate::network::rooms::RoomManager;
+use crate::network::sessions::Sessions;
+use crate::network::slots::UploadSlots;
+use crate::settings::{self, SharedSettings};
+use crate::watcher::ShareWatcher;
 use iroh_blobs::net_protocol::Blobs;
+use iroh_docs::protocol::Docs;
+use iroh_gossip::net::Gossip;
 
 #[derive(Debug)]
 pub struct AppState {
     pub router: Option<iroh::protocol::Router>,
+    pub blobs: Option<Blobs<iroh_blobs::store::fs::Store>>,
     username: Option<String>,
     discovery_task: Option<tokio::task::JoinHandle<()>>,
     pub file_protocol: Option<FileProtocol>,
+    pub transfers: Option<TransferManager>,
+    pub watcher: Option<ShareWatcher>,
+    pub rooms: Option<RoomManager>,
+    pub outbox: Option<Outbox>,
+    pub moderation: Option<Moderation>,
+    pub bandwidth: Option<Bandwidth>,
+    pub slots: Option<UploadSlots>,
+    pub sessions: Option<Sessions>,
     pub peers: Arc<Mutex<Vec<Peer>>>,
+    pub favourites: Favourites,
+    pub settings: SharedSettings,
 }
 
 pub struct AppStateWrapper(pub Arc<Mutex<AppState>>);
@@ -27,9 +54,20 @@ impl AppState {
         Ok(Self {
             peers: Arc::new(Mutex::new(Vec::new())),
             router: None,
-            username: None,
+            blobs: None,
+            username: identity::load_username(),
             discovery_task: None,
             file_protocol: None,
+            transfers: None,
+            watcher: None,
+            rooms: None,
+            outbox: None,
+            moderation: None,
+            bandwidth: None,
+            slots: None,
+            sessions: None,
+            favourites: Favourites::load(),
+            settings: Arc::new(Mutex::new(settings::load())),
         })
     }
 
@@ -38,27 +76,116 @@ impl AppState {
             return Ok(());
         }
         let blobs_data_dir = crate::global::APP_DATA_DIR.join("blobs");
-        let endpoint = Endpoint::builder().discovery_local_network().bind().await?;
+        let endpoint = Endpoint::builder()
+            .secret_key(identity::load_secret_key()?)
+            .discovery_local_network()
+            .bind()
+            .await?;
+        // Share visibility, upload slots and upload limits are enforced on every blob
+        // request, see `GatedBlobs`
+        let bandwidth = Bandwidth::new(Arc::clone(&self.settings));
+        let slots = UploadSlots::new(Arc::clone(&self.settings));
+        let gate = BlobsGate::new(bandwidth.clone(), slots.clone(), self.favourites.clone());
         let blobs = Blobs::persistent(&blobs_data_dir).await?.build(&endpoint);
 
-        // TODO Recover uploaded_files from previous session
-        let proto = FileProtocol::new(blobs.client().clone(), app);
+        let moderation = Moderation::spawn(endpoint.clone(), Arc::clone(&self.peers), app.clone());
+        let proto = FileProtocol::new(
+            blobs.client().clone(),
+            Arc::clone(&self.settings),
+            moderation.clone(),
+            slots.clone(),
+            self.favourites.clone(),
+            app.clone(),
+        )
+        .await?;
+        gate.set_protocol(proto.clone());
+
+        // Our shares survive restarts in the blob store, but the files they reference
+        // may have been moved or edited while we were away
+        integrity::spawn_checker(proto.clone(), app.clone());
+        let watcher = ShareWatcher::spawn(proto.clone(), app.clone()).await?;
+
+        // Chat rooms are iroh-docs documents, synced over gossip
+        let gossip = Gossip::builder().spawn(endpoint.clone()).await?;
+        let docs = Docs::persistent(crate::global::APP_DATA_DIR.join("docs"))
+            .spawn(&blobs, &gossip)
+            .await?;
+        let rooms = RoomManager::spawn(
+            docs.client().clone(),
+            blobs.client().clone(),
+            endpoint.clone(),
+            Arc::clone(&self.peers),
+            app.clone(),
+        )
+        .await?;
+        gate.set_rooms(rooms.clone());
+        // Requests to peers reuse one connection per peer
+        let sessions = Sessions::spawn(endpoint.clone(), &app);
+        slots.notify_peers(sessions.clone());
+        let outbox = Outbox::spawn(
+            proto.chat().clone(),
+            sessions.clone(),
+            Arc::clone(&self.peers),
+            &app,
+        );
+        let transfers = TransferManager::spawn(
+            blobs.clone(),
+            Arc::clone(&self.peers),
+            bandwidth.clone(),
+            app,
+        );
         let router = Router::builder(endpoint.clone())
-            .accept(iroh_blobs::ALPN, blobs.clone())
+            .accept(iroh_blobs::ALPN, GatedBlobs::new(blobs.clone(), gate))
             .accept(ALPN, proto.clone())
+            .accept(iroh_gossip::ALPN, gossip)
+            .accept(iroh_docs::ALPN, docs)
             .spawn();
 
+        for favourite in self.favourites.list() {
+            if let Err(err) = favourites::insert(&endpoint, &self.peers, &favourite).await {
+                warn!("Could not restore favourite {}: {err:#}", favourite.node_id);
+            }
+        }
+
         self.router = Some(router);
+        self.blobs = Some(blobs);
         self.file_protocol = Some(proto.clone());
+        self.transfers = Some(transfers);
+        self.watcher = Some(watcher);
+        self.rooms = Some(rooms);
+        self.outbox = Some(outbox);
+        self.moderation = Some(moderation);
+        self.bandwidth = Some(bandwidth);
+        self.slots = Some(slots);
+        self.sessions = Some(sessions);
         Ok(())
     }
 
+    /// Closes our sessions with other peers and stops the endpoint, before the app exits.
+    pub async fn go_offline(&mut self) {
+        if let Some(discovery) = self.discovery_task.take() {
+            discovery.abort();
+        }
+        if let Some(moderation) = &self.moderation {
+            moderation.shutdown();
+        }
+        if let Some(sessions) = self.sessions.take() {
+            sessions.shutdown().await;
+        }
+        if let Some(router) = self.router.take() {
+            if let Err(err) = router.shutdown().await {
+                warn!("Failed to shut down the endpoint: {err:#}");
+            }
+        }
+    }
+
     pub fn update_username(&mut self, username: String) -> Result<()> {
         match &mut self.router {
             Some(router) => {
                 router
                     .endpoint()
                     .set_user_data_for_discovery(Some(username.clone().try_into()?));
+                identity::save_username(&username)?;
                 self.username = Some(username);
                 Ok(())
             }
@@ -105,6 +232,73 @@ impl AppState {
         self.discovery_task = Some(handle);
     }
 
+    /// Adds a peer by hand and keeps it as a favourite across restarts.
+    pub async fn add_favourite(
+        &self,
+        app: &AppHandle,
+        node_addr: iroh::NodeAddr,
+        name: Option<String>,
+    ) -> Result<PeerSerializable> {
+        let endpoint = self.router.as_ref().context("No endpoint")?.endpoint();
+        anyhow::ensure!(
+            node_addr.node_id != endpoint.node_id(),
+            "Cannot add ourselves as a peer"
+        );
+        if let Some(moderation) = &self.moderation {
+            if let Some(ban) = moderation.ban_for(node_addr.node_id, None).await {
+                anyhow::bail!("Peer is banned by the host: {}", ban.reason);
+            }
+        }
+        // A peer already found by discovery is kept with the addresses it was seen at
+        let known = self
+            .peers
+            .lock()
+            .await
+            .iter()
+            .find(|peer| peer.node_addr.node_id == node_addr.node_id)
+            .cloned();
+        let mut addresses: Vec<_> = node_addr.direct_addresses.iter().copied().collect();
+        if addresses.is_empty() {
+            if let Some(known) = &known {
+                addresses = known.node_addr.direct_addresses.iter().copied().collect();
+            }
+        }
+        let favourite = Favourite {
+            node_id: node_addr.node_id,
+            addresses,
+            name: name
+                .or_else(|| known.map(|peer| peer.username))
+                .unwrap_or_else(|| node_addr.node_id.fmt_short()),
+        };
+        self.favourites.add(favourite.clone()).await?;
+
+        if let Some(peer) = favourites::insert(endpoint, &self.peers, &favourite).await? {
+            let payload: PeerSerializable = peer.into();
+            let _ = app.emit("peer::added", payload);
+        }
+        let peers = self.peers.lock().await;
+        let peer = peers
+            .iter()
+            .find(|peer| peer.node_addr.node_id == favourite.node_id)
+            .context("Peer not found")?;
+        Ok(peer.clone().into())
+    }
+
+    /// Forgets a favourite, discovery removes the peer once it is no longer seen.
+    pub async fn remove_favourite(&self, node_id: iroh::NodeId) -> Result<()> {
+        self.favourites.remove(node_id).await?;
+        if let Some(peer) = self
+            .peers
+            .lock()
+            .await
+            .iter_mut()
+            .find(|peer| peer.node_addr.node_id == node_id)
+        {
+            peer.favourite = false;
+        }
+        Ok(())
+    }
+
     pub async fn get_node_addr(&self, node_id: iroh::NodeId) -> Result<iroh::NodeAddr> {
         self.peers
             .lock()
@@ -126,12 +320,21 @@ pub struct Peer {
     pub username: String,
     pub node_addr: iroh::NodeAddr,
     pub last_seen: tokio::time::Instant,
+    /// Whether the host confirmed that `username` is registered to this node.
+    pub verified: bool,
+    /// Added by hand, never removed when discovery stops seeing the peer.
+    pub favourite: bool,
+    /// Not seen within the discovery timeout, but still within the grace period.
+    pub away: bool,
 }
 
-#[derive(Clone, serde::Serialize, Debug)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
 pub struct PeerSerializable {
     pub username: String,
     pub node_id: iroh::NodeId,
+    pub verified: bool,
+    pub favourite: bool,
+    pub away: bool,
 }
 
 impl From<Peer> for PeerSerializable {
@@ -139,6 +342,9 @@ impl From<Peer> for PeerSerializable {
         Self {
             username: peer.username,
             node_id: peer.node_addr.node_id,
+            verified: peer.verified,
+            favourite: peer.favourite,
+            away: peer.away,
         }
     }
 }
@@ -1 +1,161 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
 
+/// A file found while scanning a path for import.
+#[derive(Debug, Clone)]
+pub struct DataSource {
+    /// Name inside the collection, `/` separated and prefixed with the root's file name.
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Recursively lists all files below `root`.
+///
+/// Names are relative to the parent of `root`, so importing `/home/me/notes` yields
+/// entries like `notes/week1.pdf`, and a single file is named like the file.
+pub fn scan_path(root: &Path) -> Result<Vec<DataSource>> {
+    let root_name = root
+        .file_name()
+        .context("Path has no file name")?
+        .to_str()
+        .context("Not a valid UTF-8 path")?
+        .to_owned();
+    scan_named(root, root_name)
+}
+
+/// Recursively lists all files below `path`, named below `name`.
+///
+/// Symlinks and special files such as sockets or FIFOs are skipped.
+pub fn scan_named(path: &Path, name: String) -> Result<Vec<DataSource>> {
+    let mut sources = Vec::new();
+    scan_into(path, name, &mut sources)?;
+    Ok(sources)
+}
+
+fn scan_into(path: &Path, name: String, sources: &mut Vec<DataSource>) -> Result<()> {
+    // Not following symlinks also keeps us out of link cycles
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_file() && !metadata.is_dir() {
+        debug!("Skipping {}, not a regular file", path.display());
+        return Ok(());
+    }
+    if metadata.is_file() {
+        sources.push(DataSource {
+            name,
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::from),
+        });
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_str().context("Not a valid UTF-8 path")?;
+        scan_into(&entry.path(), format!("{name}/{file_name}"), sources)?;
+    }
+    Ok(())
+}
+
+/// Joins `relative`, a path received from a peer, onto `base`.
+///
+/// Fails unless `relative` only consists of plain names, so a peer cannot make us
+/// write outside of `base` with absolute paths or `..`.
+pub fn safe_join(base: &Path, relative: impl AsRef<Path>) -> Result<PathBuf> {
+    let relative = relative.as_ref();
+    let mut components = relative.components().peekable();
+    anyhow::ensure!(
+        components.peek().is_some() && components.all(|c| matches!(c, Component::Normal(_))),
+        "Refusing unsafe path {} from peer",
+        relative.display()
+    );
+    Ok(base.join(relative))
+}
+
+/// Matches `text` against a glob `pattern` where `*` matches any run of characters
+/// and `?` matches exactly one character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_stars_match_any_run() {
+        assert!(glob_match("*.mp3", "song.mp3"));
+        assert!(glob_match("*.mp3", ".mp3"));
+        assert!(!glob_match("*.mp3", "song.mp4"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(glob_match("*ab", "aab"));
+        assert!(!glob_match("a*c", "abcd"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "anything"));
+    }
+
+    #[test]
+    fn glob_question_marks_match_one_character() {
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("a?c", "abbc"));
+        assert!(glob_match("?", "é"));
+    }
+
+    #[test]
+    fn glob_without_wildcards_matches_exactly() {
+        assert!(glob_match("song.mp3", "song.mp3"));
+        assert!(!glob_match("song.mp3", "Song.mp3"));
+        assert!(!glob_match("", "x"));
+        assert!(glob_match("", ""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_skips_symlinks_and_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("share");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        std::fs::write(root.join("sub/b.txt"), "bb").unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+        std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let mut sources = scan_path(&root).unwrap();
+        sources.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = sources.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["share/a.txt", "share/sub/b.txt"]);
+        assert_eq!(sources[1].size, 2);
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+use crate::access::Visibility;
+use crate::network::protocol::FileProtocol;
+
+const WATCHED_FILE: &str = "watched.json";
+/// Changes are collected for this long before a share is updated, so copying a folder
+/// into a share triggers a single update.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Payload of `share::updated`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareUpdated {
+    pub tag: String,
+    /// Hash of the collection after the update.
+    pub hash: String,
+}
+
+/// A share, whether it is watched and who may see it, as listed by `list_shares`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareSummary {
+    pub tag: String,
+    pub watched: bool,
+    pub visibility: Visibility,
+}
+
+struct Inner {
+    debouncer: Debouncer<RecommendedWatcher>,
+    /// Tags of the shares being watched, which are their absolute paths.
+    watched: BTreeSet<String>,
+}
+
+/// Watches the shares the user opted in for and imports the files that changed below
+/// them again.
+#[derive(Clone)]
+pub struct ShareWatcher {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl std::fmt::Debug for ShareWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShareWatcher").finish_non_exhaustive()
+    }
+}
+
+impl ShareWatcher {
+    /// Starts watching the shares watched in the previous session.
+    pub async fn spawn(file_protocol: FileProtocol, app: AppHandle) -> Result<Self> {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        // Called from the debouncer's own thread
+        let debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| match result {
+            Ok(events) => {
+                let _ = events_tx.send(events.into_iter().map(|e| e.path).collect::<Vec<_>>());
+            }
+            Err(err) => warn!("File watcher error: {err}"),
+        })?;
+
+        let shared = file_protocol.shared_tags().await;
+        let mut inner = Inner {
+            debouncer,
+            watched: BTreeSet::new(),
+        };
+        for tag in load_watched() {
+            if !shared.contains(&tag) {
+                continue;
+            }
+            match inner.watch(&tag) {
+                Ok(()) => {
+                    inner.watched.insert(tag);
+                }
+                Err(err) => warn!("Could not watch {tag}: {err:#}"),
+            }
+        }
+        let watcher = Self {
+            inner: Arc::new(Mutex::new(inner)),
+        };
+        tokio::spawn(watcher.clone().handle_events(events_rx, file_protocol, app));
+        Ok(watcher)
+    }
+
+    pub async fn watched(&self) -> BTreeSet<String> {
+        self.inner.lock().await.watched.clone()
+    }
+
+    /// Turns watching of the share under `tag` on or off.
+    pub async fn set_watched(&self, tag: &str, enabled: bool) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        if enabled && !inner.watched.contains(tag) {
+            inner.watch(tag)?;
+            inner.watched.insert(tag.to_owned());
+        } else if !enabled && inner.watched.remove(tag) {
+            if let Err(err) = inner.debouncer.watcher().unwatch(Path::new(tag)) {
+                warn!("Could not stop watching {tag}: {err}");
+            }
+        }
+        save_watched(&inner.watched).await
+    }
+
+    async fn handle_events(
+        self,
+        mut events_rx: mpsc::UnboundedReceiver<Vec<PathBuf>>,
+        file_protocol: FileProtocol,
+        app: AppHandle,
+    ) {
+        while let Some(paths) = events_rx.recv().await {
+            let watched = self.watched().await;
+            for tag in &watched {
+                let changed: Vec<PathBuf> = paths
+                    .iter()
+                    .filter(|path| path.starts_with(tag.as_str()))
+                    .cloned()
+                    .collect();
+                if changed.is_empty() {
+                    continue;
+                }
+                if !file_protocol.shared_tags().await.contains(tag) {
+                    // The share was removed since we started watching it
+                    let _ = self.set_watched(tag, false).await;
+                    continue;
+                }
+                match file_protocol.update_paths(tag, &changed).await {
+                    Ok(hash) => {
+                        info!("Updated watched share {tag}");
+                        let payload = ShareUpdated {
+                            tag: tag.clone(),
+                            hash: hash.to_string(),
+                        };
+                        let _ = app.emit("share::updated", payload);
+                    }
+                    Err(err) => warn!("Failed to update watched share {tag}: {err:#}"),
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn watch(&mut self, tag: &str) -> Result<()> {
+        self.debouncer
+            .watcher()
+            .watch(Path::new(tag), RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {tag}"))
+    }
+}
+
+fn load_watched() -> Vec<String> {
+    let path = crate::global::APP_DATA_DIR.join(WATCHED_FILE);
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+            warn!("Discarding unreadable list of watched shares: {err}");
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+async fn save_watched(watched: &BTreeSet<String>) -> Result<()> {
+    let path = crate::global::APP_DATA_DIR.join(WATCHED_FILE);
+    let data = serde_json::to_vec_pretty(watched)?;
+    tokio::fs::write(&path, data)
+        .await
+        .context("Failed to save watched shares")?;
+    Ok(())
+}
@@ -2,6 +2,45 @@
 export interface Peer {
   username: string;
   node_id: string;
+  verified: boolean;
+  favourite: boolean;
+  away: boolean;
+}
+export interface Settings {
+  version: number;
+  download_dir: string | null;
+  io_parallelism: number;
+  bandwidth: {
+    upload_limit_kib: number | null;
+    download_limit_kib: number | null;
+    peer_upload_limit_kib: number | null;
+    peer_download_limit_kib: number | null;
+  };
+  uploads: {
+    slots: number;
+    favourite_slots: number;
+  };
+  discovery: {
+    timeout_secs: number;
+    sweep_interval_secs: number;
+    away_grace_secs: number;
+  };
+  privacy: {
+    share_listing: boolean;
+    accept_messages: boolean;
+  };
+}
+export interface BandwidthStats {
+  upload_bytes_per_sec: number;
+  download_bytes_per_sec: number;
+  total_uploaded: number;
+  total_downloaded: number;
+  peers: Array<{
+    node_id: string;
+    upload_bytes_per_sec: number;
+    download_bytes_per_sec: number;
+  }>;
+  limits: Settings["bandwidth"];
 }
 export interface TreeNode {
   name: string;